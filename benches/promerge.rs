@@ -0,0 +1,187 @@
+use std::fmt::Write;
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use promerge::promerge::Context;
+
+/// Builds an exposition resembling a node_exporter scrape.
+fn node_exporter() -> String {
+    let mut out = String::new();
+    let modes = [
+        "idle", "iowait", "irq", "nice", "softirq", "steal", "system", "user",
+    ];
+    out.push_str("# HELP node_cpu_seconds_total Seconds the CPUs spent in each mode.\n");
+    out.push_str("# TYPE node_cpu_seconds_total counter\n");
+    for cpu in 0..32 {
+        for mode in modes {
+            writeln!(
+                out,
+                "node_cpu_seconds_total{{cpu=\"{}\",mode=\"{}\"}} {}.{}",
+                cpu,
+                mode,
+                cpu * 1000 + 17,
+                cpu
+            )
+            .unwrap();
+        }
+    }
+    out.push_str("# HELP node_filesystem_avail_bytes Filesystem space available to non-root users in bytes.\n");
+    out.push_str("# TYPE node_filesystem_avail_bytes gauge\n");
+    for disk in 0..24 {
+        writeln!(
+            out,
+            "node_filesystem_avail_bytes{{device=\"/dev/sd{}\",fstype=\"ext\",mountpoint=\"/mnt/{}\"}} {}",
+            disk,
+            disk,
+            disk * 1_048_576 + 4096
+        )
+        .unwrap();
+    }
+    out.push_str(
+        "# HELP node_network_receive_bytes_total Network device statistic receive_bytes.\n",
+    );
+    out.push_str("# TYPE node_network_receive_bytes_total counter\n");
+    for device in 0..16 {
+        writeln!(
+            out,
+            "node_network_receive_bytes_total{{device=\"eth{}\"}} {}",
+            device,
+            device * 7_340_033 + 1
+        )
+        .unwrap();
+    }
+    out.push_str("# HELP go_gc_duration_seconds A summary of the pause duration of garbage collection cycles.\n");
+    out.push_str("# TYPE go_gc_duration_seconds summary\n");
+    for quantile in ["0", "0.25", "0.5", "0.75", "1"] {
+        writeln!(
+            out,
+            "go_gc_duration_seconds{{quantile=\"{}\"}} 3.2e-05",
+            quantile
+        )
+        .unwrap();
+    }
+    out.push_str("go_gc_duration_seconds_sum 0.35\ngo_gc_duration_seconds_count 1250\n");
+    out
+}
+
+/// Builds an exposition resembling kube-state-metrics of a
+/// cluster running `pods` pods.
+fn kube_state_metrics(pods: usize) -> String {
+    let mut out = String::new();
+    let phases = ["Pending", "Running", "Succeeded", "Failed", "Unknown"];
+    out.push_str("# HELP kube_pod_info Information about pod.\n");
+    out.push_str("# TYPE kube_pod_info gauge\n");
+    for pod in 0..pods {
+        writeln!(
+            out,
+            "kube_pod_info{{namespace=\"ns-{}\",pod=\"pod-{}\",node=\"node-{}\",ip=\"10.0.{}.{}\"}} 1",
+            pod % 40,
+            pod,
+            pod % 100,
+            pod / 256,
+            pod % 256
+        )
+        .unwrap();
+    }
+    out.push_str("# HELP kube_pod_status_phase The pods current phase.\n");
+    out.push_str("# TYPE kube_pod_status_phase gauge\n");
+    for pod in 0..pods {
+        for (idx, phase) in phases.iter().enumerate() {
+            writeln!(
+                out,
+                "kube_pod_status_phase{{namespace=\"ns-{}\",pod=\"pod-{}\",phase=\"{}\"}} {}",
+                pod % 40,
+                pod,
+                phase,
+                (idx == 1) as u8
+            )
+            .unwrap();
+        }
+    }
+    out
+}
+
+/// Builds a histogram family with many labeled series.
+fn large_histogram() -> String {
+    let mut out = String::new();
+    let buckets = [
+        "0.005", "0.01", "0.025", "0.05", "0.1", "0.25", "0.5", "1", "2.5", "5", "10", "+Inf",
+    ];
+    out.push_str("# HELP http_request_duration_seconds A histogram of the request duration.\n");
+    out.push_str("# TYPE http_request_duration_seconds histogram\n");
+    for handler in 0..200 {
+        for method in ["get", "post", "put", "delete"] {
+            for (idx, le) in buckets.iter().enumerate() {
+                writeln!(
+                    out,
+                    "http_request_duration_seconds_bucket{{handler=\"/api/v{}\",method=\"{}\",le=\"{}\"}} {}",
+                    handler,
+                    method,
+                    le,
+                    (idx + 1) * 100
+                )
+                .unwrap();
+            }
+        }
+    }
+    out.push_str("http_request_duration_seconds_sum 53423\n");
+    out.push_str("http_request_duration_seconds_count 1200\n");
+    out
+}
+
+fn corpora() -> Vec<(&'static str, String)> {
+    vec![
+        ("node_exporter", node_exporter()),
+        ("kube_state_metrics", kube_state_metrics(5000)),
+        ("large_histogram", large_histogram()),
+    ]
+}
+
+fn bench_parse(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parse");
+    for (name, corpus) in corpora() {
+        group.throughput(Throughput::Bytes(corpus.len() as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(name), &corpus, |b, corpus| {
+            b.iter(|| promerge::parse(black_box(corpus)).unwrap().len())
+        });
+    }
+    group.finish();
+}
+
+fn bench_run(c: &mut Criterion) {
+    let mut group = c.benchmark_group("context_run");
+    for (name, corpus) in corpora() {
+        group.throughput(Throughput::Bytes(corpus.len() as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(name), &corpus, |b, corpus| {
+            b.iter(|| {
+                let mut ctx = Context::with_prefix(black_box(corpus), "prefix_");
+                ctx.run().unwrap().len()
+            })
+        });
+    }
+    group.finish();
+}
+
+fn bench_combine(c: &mut Criterion) {
+    let mut group = c.benchmark_group("combine_with_prefix_and_pairs");
+    let corpus = node_exporter();
+    for sources in [10, 50] {
+        let pairs: Vec<[(String, String); 1]> = (0..sources)
+            .map(|idx| [("instance".to_string(), format!("node-{}", idx))])
+            .collect();
+        group.throughput(Throughput::Bytes((corpus.len() * sources) as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(sources), &pairs, |b, pairs| {
+            b.iter(|| {
+                let mut ctx = Context::new("");
+                for pairs in pairs {
+                    ctx.combine_with_prefix_and_pairs(black_box(&corpus), pairs, "node_")
+                        .unwrap();
+                }
+                ctx.result().len()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_parse, bench_run, bench_combine);
+criterion_main!(benches);
@@ -0,0 +1,768 @@
+//! Module containing logic to reduce series across merged inputs.
+use std::collections::HashMap;
+
+use crate::intern::Label;
+use crate::promerge::{Kind, Segment, Source, Value};
+use crate::relabel::{to_pairs, to_sample_pairs};
+
+type Labels = Vec<(String, String)>;
+
+/// Operator reduces the samples of matching series.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operator {
+    Sum,
+    Min,
+    Max,
+    Avg,
+    /// Keeps the sample of the input merged last.
+    Last,
+    /// Counts the number of matching series.
+    Count,
+}
+
+/// BucketMerge decides which cumulative count is assigned to
+/// a histogram bucket boundary that an input does not expose.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BucketMerge {
+    /// Uses the count of the closest lower boundary, so the
+    /// number of observations is never overstated.
+    #[default]
+    Conservative,
+    /// Interpolates linearly between the surrounding boundaries.
+    Interpolate,
+}
+
+/// QuantilePolicy decides what happens to the quantile series of
+/// summaries, whose `_sum` and `_count` are summed across inputs.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum QuantilePolicy {
+    /// Drops quantile series.
+    Drop,
+    /// Keeps quantile series of every input, adding a label with
+    /// the given name whose value is the name of the source, or
+    /// the position of inputs merged without a name.
+    Source(String),
+    /// Drops quantile series and lists the family in
+    /// `Context::non_aggregatable_families`.
+    #[default]
+    Report,
+}
+
+/// Aggregation describes how matching series from all
+/// inputs merged into `Context` are reduced.
+///
+/// Histogram buckets and the `_sum`/`_count` lines of
+/// histograms and summaries are always summed after
+/// dropping the configured labels. Histograms with differing
+/// bucket layouts are merged over the union of their
+/// boundaries according to `BucketMerge`. Quantiles of
+/// summaries follow `QuantilePolicy`. Other families are
+/// reduced by the operator configured for their name or
+/// kind, and passed through when there is none. Aggregated
+/// samples carry no timestamp.
+#[derive(Debug, Clone, Default)]
+pub struct Aggregation {
+    without: Vec<String>,
+    kinds: HashMap<Kind, Operator>,
+    families: HashMap<String, Operator>,
+    buckets: BucketMerge,
+    quantiles: QuantilePolicy,
+}
+
+/// Series holds the reduced sample of a single label set.
+#[derive(Debug, Clone)]
+struct Series {
+    labels: Labels,
+    value: f64,
+    samples: usize,
+}
+
+/// Group collects series sharing the same label set,
+/// keeping the order they were first seen in.
+#[derive(Debug, Clone, Default)]
+struct Group {
+    index: HashMap<Labels, usize>,
+    series: Vec<Series>,
+}
+
+/// Buckets holds the cumulative counts of a histogram
+/// series, per input and keyed by upper bound.
+#[derive(Debug, Clone)]
+struct Buckets {
+    labels: Labels,
+    position: usize,
+    bounds: Vec<(f64, String)>,
+    inputs: Vec<Vec<(f64, f64)>>,
+}
+
+/// Histogram collects buckets sharing the same labels,
+/// ignoring `le`.
+#[derive(Debug, Clone, Default)]
+struct Histogram {
+    index: HashMap<Labels, usize>,
+    series: Vec<Buckets>,
+}
+
+/// Family accumulates every input contributing to
+/// a single metric family.
+#[derive(Debug, Clone)]
+struct Family {
+    name: String,
+    /// the family as first seen, without its series.
+    first: Value<'static>,
+    operator: Operator,
+    /// summaries holding quantiles, by input and position.
+    quantiles: Vec<(usize, usize)>,
+    histogram: Histogram,
+    series: Group,
+    sum: Group,
+    count: Group,
+}
+
+/// Aggregated holds the reduced values and the names
+/// of families whose samples are approximate or could
+/// not be aggregated.
+#[derive(Debug)]
+pub(crate) struct Aggregated<'a> {
+    pub(crate) values: Vec<Value<'a>>,
+    pub(crate) approximate: Vec<String>,
+    pub(crate) non_aggregatable: Vec<String>,
+}
+
+/// Entry keeps the position of passed through values,
+/// by input and position within it, and of aggregated
+/// families in the output.
+#[derive(Debug, Clone)]
+enum Entry {
+    Pass(usize, usize),
+    Family(usize),
+}
+
+/// Aggregator folds in the families of one input after
+/// another, so that merging a further input does not reduce
+/// the previous ones again.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Aggregator {
+    entries: Vec<Entry>,
+    families: Vec<Family>,
+    index: HashMap<String, usize>,
+    inputs: usize,
+}
+
+impl Aggregation {
+    /// Sum matching series after removing `labels`
+    /// (e.g. `instance` or `pod`) from every series.
+    pub fn sum_without<I, S>(labels: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Self {
+            without: labels.into_iter().map(Into::into).collect(),
+            kinds: HashMap::from([(Kind::Counter, Operator::Sum)]),
+            families: HashMap::new(),
+            buckets: BucketMerge::default(),
+            quantiles: QuantilePolicy::default(),
+        }
+    }
+
+    /// Handles quantile series of summaries according to `quantiles`.
+    pub fn with_quantile_policy(mut self, quantiles: QuantilePolicy) -> Self {
+        self.quantiles = quantiles;
+        self
+    }
+
+    /// Merges histograms with differing bucket layouts using `buckets`.
+    pub fn with_bucket_merge(mut self, buckets: BucketMerge) -> Self {
+        self.buckets = buckets;
+        self
+    }
+
+    /// Reduces families of `kind` with `operator`.
+    pub fn with_kind(mut self, kind: Kind, operator: Operator) -> Self {
+        self.kinds.insert(kind, operator);
+        self
+    }
+
+    /// Reduces the family called `name` with `operator`. The
+    /// name is matched with and without the prefix of the input.
+    pub fn with_family<S: Into<String>>(mut self, name: S, operator: Operator) -> Self {
+        self.families.insert(name.into(), operator);
+        self
+    }
+
+    fn operator(&self, value: &Value<'_>) -> Option<Operator> {
+        let kind = value.kind();
+        if matches!(kind, Kind::Histogram | Kind::Summary) {
+            return Some(Operator::Sum);
+        }
+        let name = value.family_name();
+        let prefixed = format!("{}{}", value.prefix.as_deref().unwrap_or(""), name);
+        self.families
+            .get(&prefixed)
+            .or_else(|| self.families.get(name))
+            .or_else(|| self.kinds.get(&kind))
+            .copied()
+    }
+
+    fn labels<'b, I>(&self, pairs: I) -> Labels
+    where
+        I: IntoIterator<Item = &'b (Label<'b>, Label<'b>)>,
+    {
+        pairs
+            .into_iter()
+            .filter(|(k, _)| !k.is_empty() && !self.without.iter().any(|w| w == k))
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+}
+
+impl Series {
+    fn add(&mut self, value: f64, operator: Operator) {
+        self.samples += 1;
+        self.value = match operator {
+            Operator::Sum | Operator::Avg => self.value + value,
+            Operator::Min => self.value.min(value),
+            Operator::Max => self.value.max(value),
+            Operator::Last => value,
+            Operator::Count => self.value,
+        };
+    }
+
+    fn result(&self, operator: Operator) -> f64 {
+        match operator {
+            Operator::Avg => self.value / self.samples as f64,
+            Operator::Count => self.samples as f64,
+            _ => self.value,
+        }
+    }
+}
+
+impl Group {
+    fn add(&mut self, labels: Labels, value: f64, operator: Operator) {
+        let mut key = labels.clone();
+        key.sort();
+        match self.index.get(&key) {
+            Some(&idx) => self.series[idx].add(value, operator),
+            None => {
+                self.index.insert(key, self.series.len());
+                self.series.push(Series {
+                    labels,
+                    value,
+                    samples: 1,
+                });
+            }
+        }
+    }
+}
+
+impl Histogram {
+    fn add(&mut self, value: &Value<'_>, aggregation: &Aggregation) {
+        // maps series of this histogram to its input slot.
+        let mut inputs: HashMap<usize, usize> = HashMap::new();
+        for (pairs, sample) in value.pairs.iter().zip(value.values.iter()) {
+            let mut labels = aggregation.labels(pairs);
+            let Some(position) = labels.iter().position(|(k, _)| k == "le") else {
+                continue;
+            };
+            let (_, le) = labels.remove(position);
+            let bound = parse_float(&le);
+            let mut key = labels.clone();
+            key.sort();
+            let idx = *self.index.entry(key).or_insert_with(|| {
+                self.series.push(Buckets {
+                    labels,
+                    position,
+                    bounds: Vec::new(),
+                    inputs: Vec::new(),
+                });
+                self.series.len() - 1
+            });
+            let buckets = &mut self.series[idx];
+            let input = *inputs.entry(idx).or_insert_with(|| {
+                buckets.inputs.push(Vec::new());
+                buckets.inputs.len() - 1
+            });
+            if !buckets.bounds.iter().any(|(b, _)| *b == bound) {
+                buckets.bounds.push((bound, le));
+            }
+            buckets.inputs[input].push((bound, parse_float(&sample.0)));
+        }
+    }
+}
+
+impl Buckets {
+    /// Sums the counts of all inputs over the union of their
+    /// boundaries. Returns the merged series and whether any
+    /// count had to be estimated.
+    fn merge(&self, mode: BucketMerge, count: Option<f64>) -> (Vec<Series>, bool) {
+        let mut bounds = self.bounds.clone();
+        bounds.sort_by(|a, b| a.0.total_cmp(&b.0));
+        let has_inf = bounds.last().is_some_and(|b| b.0 == f64::INFINITY);
+        if count.is_some() && !has_inf {
+            bounds.push((f64::INFINITY, "+Inf".into()));
+        }
+
+        let mut approximate = false;
+        let mut totals = vec![0.0; bounds.len()];
+        for input in &self.inputs {
+            let mut input = input.clone();
+            input.sort_by(|a, b| a.0.total_cmp(&b.0));
+            for (i, (bound, _)) in bounds.iter().enumerate() {
+                totals[i] += match input.iter().find(|b| b.0 == *bound) {
+                    Some(b) => b.1,
+                    // +Inf is replaced by the merged `_count` below.
+                    None if *bound == f64::INFINITY && count.is_some() => 0.0,
+                    None => {
+                        approximate = true;
+                        estimate(&input, *bound, mode)
+                    }
+                };
+            }
+        }
+        if let (Some(count), Some(last)) = (count, totals.last_mut()) {
+            *last = count;
+        }
+
+        let series = bounds
+            .into_iter()
+            .zip(totals)
+            .map(|((_, le), value)| {
+                let mut labels = self.labels.clone();
+                labels.insert(self.position.min(labels.len()), ("le".into(), le));
+                Series {
+                    labels,
+                    value,
+                    samples: 1,
+                }
+            })
+            .collect();
+        (series, approximate)
+    }
+}
+
+impl Family {
+    fn new(name: String, first: &Value<'_>, operator: Operator) -> Self {
+        let mut header = Value::new(first.key.clone());
+        header.prefix = first.prefix.clone();
+        header.description = first.description.clone().map(|d| d.into_owned());
+        Self {
+            name,
+            first: header,
+            operator,
+            quantiles: Vec::new(),
+            histogram: Histogram::default(),
+            series: Group::default(),
+            sum: Group::default(),
+            count: Group::default(),
+        }
+    }
+
+    fn add(&mut self, input: (usize, usize), value: &Value<'_>, aggregation: &Aggregation) {
+        if matches!(value.kind(), Kind::Summary) {
+            self.quantiles.push(input);
+        } else if matches!(value.kind(), Kind::Histogram) {
+            self.histogram.add(value, aggregation);
+        } else {
+            for (pairs, sample) in value.pairs.iter().zip(value.values.iter()) {
+                self.series.add(
+                    aggregation.labels(pairs),
+                    parse_float(&sample.0),
+                    self.operator,
+                );
+            }
+        }
+        for sum in &value.sum {
+            self.sum.add(
+                aggregation.labels(&sum.pairs),
+                parse_float(&sum.value),
+                Operator::Sum,
+            );
+        }
+        for count in &value.count {
+            self.count.add(
+                aggregation.labels(&count.pairs),
+                parse_float(&count.value),
+                Operator::Sum,
+            );
+        }
+    }
+
+    /// Merges the histogram buckets of all inputs into series,
+    /// along with whether any count had to be estimated.
+    fn merge_buckets(&self, mode: BucketMerge) -> (Vec<Series>, bool) {
+        let mut approximate = false;
+        let mut series = Vec::new();
+        for buckets in &self.histogram.series {
+            let mut key = buckets.labels.clone();
+            key.sort();
+            let count = self
+                .count
+                .index
+                .get(&key)
+                .map(|&idx| self.count.series[idx].value);
+            let (merged, estimated) = buckets.merge(mode, count);
+            approximate |= estimated;
+            series.extend(merged);
+        }
+        (series, approximate)
+    }
+
+    fn has_quantiles(&self, sources: &[&Source<'_>]) -> bool {
+        self.quantiles
+            .iter()
+            .any(|&(input, position)| !sources[input].as_ref()[position].values.is_empty())
+    }
+
+    /// Returns the reduced family along with whether its
+    /// histogram buckets are approximate.
+    fn to_value<'a>(
+        &self,
+        sources: &[&Source<'a>],
+        aggregation: &Aggregation,
+    ) -> (Value<'a>, bool) {
+        let mut value = Value::new(self.first.key.clone());
+        value.prefix = self.first.prefix.clone();
+        value.description = self.first.description.clone();
+        // quantiles can not be summed, at most they are kept per source.
+        if let QuantilePolicy::Source(label) = &aggregation.quantiles {
+            for &(input, position) in &self.quantiles {
+                let name = sources[input]
+                    .name()
+                    .map_or_else(|| input.to_string(), Into::into);
+                let quantile = &sources[input].as_ref()[position];
+                for (pairs, sample) in quantile.pairs.iter().zip(quantile.values.iter()) {
+                    let mut labels = aggregation.labels(pairs);
+                    labels.push((label.clone(), name.clone()));
+                    value.pairs.push(to_sample_pairs(labels));
+                    value.values.push(sample.clone());
+                }
+            }
+        }
+        let (buckets, approximate) = self.merge_buckets(aggregation.buckets);
+        for series in self.series.series.iter().chain(&buckets) {
+            let sample = format_float(series.result(self.operator));
+            value.pairs.push(to_sample_pairs(series.labels.clone()));
+            value.values.push((sample.into(), None));
+        }
+        value.sum = self.sum.series.iter().map(to_segment).collect();
+        value.count = self.count.series.iter().map(to_segment).collect();
+        (value, approximate)
+    }
+}
+
+impl Aggregator {
+    /// Returns the number of inputs folded in so far.
+    pub(crate) fn inputs(&self) -> usize {
+        self.inputs
+    }
+
+    /// Folds in the families of the next input.
+    pub(crate) fn add(&mut self, values: &[Value<'_>], aggregation: &Aggregation) {
+        let input = self.inputs;
+        self.inputs += 1;
+        for (position, value) in values.iter().enumerate() {
+            let Some(operator) = aggregation.operator(value) else {
+                self.entries.push(Entry::Pass(input, position));
+                continue;
+            };
+            let name = format!(
+                "{}{}",
+                value.prefix.as_deref().unwrap_or(""),
+                value.family_name()
+            );
+            let idx = match self.index.get(&name) {
+                Some(&idx) => idx,
+                None => {
+                    let idx = self.families.len();
+                    self.entries.push(Entry::Family(idx));
+                    self.families
+                        .push(Family::new(name.clone(), value, operator));
+                    self.index.insert(name, idx);
+                    idx
+                }
+            };
+            self.families[idx].add((input, position), value, aggregation);
+        }
+    }
+
+    /// Returns the reduced values of `sources`, which have to
+    /// be the inputs folded in, emitting each family at the
+    /// position it was first seen in.
+    pub(crate) fn finish<'a>(
+        &self,
+        sources: &[&Source<'a>],
+        aggregation: &Aggregation,
+    ) -> Aggregated<'a> {
+        let mut output = Aggregated {
+            values: Vec::with_capacity(self.entries.len()),
+            approximate: Vec::new(),
+            non_aggregatable: Vec::new(),
+        };
+        for entry in &self.entries {
+            match *entry {
+                Entry::Pass(input, position) => output
+                    .values
+                    .push(sources[input].as_ref()[position].clone()),
+                Entry::Family(idx) => {
+                    let family = &self.families[idx];
+                    let (value, approximate) = family.to_value(sources, aggregation);
+                    if approximate {
+                        output.approximate.push(family.name.clone());
+                    }
+                    if aggregation.quantiles == QuantilePolicy::Report
+                        && family.has_quantiles(sources)
+                    {
+                        output.non_aggregatable.push(family.name.clone());
+                    }
+                    output.values.push(value);
+                }
+            }
+        }
+        output
+    }
+}
+
+/// Reduces the values of all `sources` according to `aggregation`.
+pub(crate) fn aggregate<'a>(sources: &[&Source<'a>], aggregation: &Aggregation) -> Aggregated<'a> {
+    let mut aggregator = Aggregator::default();
+    for source in sources {
+        aggregator.add(source.as_ref(), aggregation);
+    }
+    aggregator.finish(sources, aggregation)
+}
+
+/// Estimates the cumulative count at `bound` from the
+/// sorted `buckets` of an input that does not expose it.
+fn estimate(buckets: &[(f64, f64)], bound: f64, mode: BucketMerge) -> f64 {
+    let lower = buckets.iter().rev().find(|b| b.0 < bound);
+    let upper = buckets.iter().find(|b| b.0 > bound);
+    let (lo, lo_count) = lower.copied().unwrap_or((bound.min(0.0), 0.0));
+    match (mode, upper) {
+        (BucketMerge::Interpolate, Some(&(hi, hi_count))) if hi.is_finite() && hi > lo => {
+            lo_count + (hi_count - lo_count) * (bound - lo) / (hi - lo)
+        }
+        _ => lo_count,
+    }
+}
+
+fn to_segment<'a>(series: &Series) -> Segment<'a> {
+    Segment {
+        value: format_float(series.result(Operator::Sum)).into(),
+        timestamp: None,
+        pairs: to_pairs(series.labels.clone()),
+    }
+}
+
+pub(crate) fn parse_float(input: &str) -> f64 {
+    input.parse::<f64>().unwrap_or(f64::NAN)
+}
+
+/// Formats `value` the way it is written in the exposition format.
+pub(crate) fn format_float(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".into()
+    } else if value.is_infinite() {
+        if value > 0.0 { "+Inf" } else { "-Inf" }.into()
+    } else if value == 0.0 || (1e-4..1e15).contains(&value.abs()) {
+        format!("{}", value)
+    } else {
+        format!("{:e}", value)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::promerge::Context;
+
+    #[test]
+    fn test_sum_without() {
+        let first = r#"# HELP http_requests_total The total number of HTTP requests.
+# TYPE http_requests_total counter
+http_requests_total{method="post",code="200"} 1027 1395066363000
+http_requests_total{method="post",code="400"} 3 1395066363000
+"#;
+        let second = r#"# HELP http_requests_total The total number of HTTP requests.
+# TYPE http_requests_total counter
+http_requests_total{method="post",code="200"} 1000
+http_requests_total{method="get",code="200"} 5
+"#;
+        let expect = r#"# HELP http_requests_total The total number of HTTP requests.
+# TYPE http_requests_total counter
+http_requests_total{method="post",code="200"} 2027
+http_requests_total{method="post",code="400"} 3
+http_requests_total{method="get",code="200"} 5
+
+"#;
+        let pairs = [("instance".into(), "a".into())];
+        let mut ctx = Context::with_prefix_and_pairs(first, "", &pairs);
+        ctx.set_aggregation(Aggregation::sum_without(["instance"]));
+        assert!(ctx.run().is_ok());
+        let output =
+            ctx.combine_with_prefix_and_pairs(second, &[("instance".into(), "b".into())], "");
+        assert_eq!(output.unwrap(), expect);
+    }
+
+    #[test]
+    fn test_sum_histogram() {
+        let input = r#"# HELP http_request_duration_seconds A histogram of the request duration.
+# TYPE http_request_duration_seconds histogram
+http_request_duration_seconds_bucket{le="0.05"} 24054
+http_request_duration_seconds_bucket{le="+Inf"} 144320
+http_request_duration_seconds_sum 53423
+http_request_duration_seconds_count 144320
+"#;
+        let expect = r#"# HELP http_request_duration_seconds A histogram of the request duration.
+# TYPE http_request_duration_seconds histogram
+http_request_duration_seconds_bucket{le="0.05"} 48108
+http_request_duration_seconds_bucket{le="+Inf"} 288640
+http_request_duration_seconds_sum 106846
+http_request_duration_seconds_count 288640
+
+"#;
+        let mut ctx = Context::new(input);
+        ctx.set_aggregation(Aggregation::sum_without(["instance"]));
+        assert!(ctx.run().is_ok());
+        let output = ctx.combine_with_prefix(input, "");
+        assert_eq!(output.unwrap(), expect);
+
+        // `_sum` and `_count` are summed per label set.
+        let input = r#"# TYPE rpc_duration_seconds histogram
+rpc_duration_seconds_bucket{method="get",le="+Inf"} 2
+rpc_duration_seconds_sum{method="get"} 4
+rpc_duration_seconds_count{method="get"} 2
+rpc_duration_seconds_bucket{method="put",le="+Inf"} 1
+rpc_duration_seconds_sum{method="put"} 6
+rpc_duration_seconds_count{method="put"} 1
+"#;
+        let expect = r#"# TYPE rpc_duration_seconds histogram
+rpc_duration_seconds_bucket{method="get",le="+Inf"} 4
+rpc_duration_seconds_bucket{method="put",le="+Inf"} 2
+rpc_duration_seconds_sum{method="get"} 8
+rpc_duration_seconds_sum{method="put"} 12
+rpc_duration_seconds_count{method="get"} 4
+rpc_duration_seconds_count{method="put"} 2
+
+"#;
+        let mut ctx = Context::new(input);
+        ctx.set_aggregation(Aggregation::sum_without(["instance"]));
+        assert!(ctx.run().is_ok());
+        let output = ctx.combine_with_prefix(input, "");
+        assert_eq!(output.unwrap(), expect);
+    }
+
+    #[test]
+    fn test_gauge_operators() {
+        let first = r#"# HELP process_resident_memory_bytes Resident memory size in bytes.
+# TYPE process_resident_memory_bytes gauge
+process_resident_memory_bytes 100
+# HELP up Whether the target is up.
+# TYPE up gauge
+up 1
+"#;
+        let second = r#"# HELP process_resident_memory_bytes Resident memory size in bytes.
+# TYPE process_resident_memory_bytes gauge
+process_resident_memory_bytes 300
+# HELP up Whether the target is up.
+# TYPE up gauge
+up 0
+"#;
+        let expect = r#"# HELP process_resident_memory_bytes Resident memory size in bytes.
+# TYPE process_resident_memory_bytes gauge
+process_resident_memory_bytes 200
+
+# HELP up Whether the target is up.
+# TYPE up gauge
+up 0
+
+"#;
+        let mut ctx = Context::new(first);
+        ctx.set_aggregation(
+            Aggregation::sum_without(["instance"])
+                .with_kind(Kind::Gauge, Operator::Avg)
+                .with_family("up", Operator::Min),
+        );
+        assert!(ctx.run().is_ok());
+        let output = ctx.combine_with_prefix(second, "");
+        assert_eq!(output.unwrap(), expect);
+    }
+
+    #[test]
+    fn test_merge_bucket_layouts() {
+        let first = r#"# HELP http_request_duration_seconds A histogram of the request duration.
+# TYPE http_request_duration_seconds histogram
+http_request_duration_seconds_bucket{le="0.1"} 10
+http_request_duration_seconds_bucket{le="1"} 19
+http_request_duration_seconds_bucket{le="+Inf"} 30
+http_request_duration_seconds_sum 12
+http_request_duration_seconds_count 30
+"#;
+        let second = r#"# HELP http_request_duration_seconds A histogram of the request duration.
+# TYPE http_request_duration_seconds histogram
+http_request_duration_seconds_bucket{le="0.5"} 4
+http_request_duration_seconds_bucket{le="+Inf"} 8
+http_request_duration_seconds_sum 3
+http_request_duration_seconds_count 8
+"#;
+        let expect = r#"# HELP http_request_duration_seconds A histogram of the request duration.
+# TYPE http_request_duration_seconds histogram
+http_request_duration_seconds_bucket{le="0.1"} 10.8
+http_request_duration_seconds_bucket{le="0.5"} 18
+http_request_duration_seconds_bucket{le="1"} 23
+http_request_duration_seconds_bucket{le="+Inf"} 38
+http_request_duration_seconds_sum 15
+http_request_duration_seconds_count 38
+
+"#;
+        let mut ctx = Context::new(first);
+        ctx.set_aggregation(
+            Aggregation::sum_without(["instance"]).with_bucket_merge(BucketMerge::Interpolate),
+        );
+        assert!(ctx.run().is_ok());
+        assert!(ctx.approximate_families().is_empty());
+        let output = ctx.combine_with_prefix(second, "");
+        assert_eq!(output.unwrap(), expect);
+        assert_eq!(
+            ctx.approximate_families(),
+            ["http_request_duration_seconds"]
+        );
+    }
+
+    #[test]
+    fn test_quantile_policy() {
+        let input = r#"# HELP rpc_duration_seconds A summary of the RPC duration in seconds.
+# TYPE rpc_duration_seconds summary
+rpc_duration_seconds{quantile="0.5"} 4773
+rpc_duration_seconds_sum 1.7560473e+07
+rpc_duration_seconds_count 2693
+"#;
+        let expect = r#"# HELP rpc_duration_seconds A summary of the RPC duration in seconds.
+# TYPE rpc_duration_seconds summary
+rpc_duration_seconds{quantile="0.5",source="a"} 4773
+rpc_duration_seconds{quantile="0.5",source="b"} 4773
+rpc_duration_seconds_sum 35120946
+rpc_duration_seconds_count 5386
+
+"#;
+        let mut ctx = Context::new(input);
+        ctx.set_aggregation(
+            Aggregation::sum_without(["instance"])
+                .with_quantile_policy(QuantilePolicy::Source("source".into())),
+        );
+        assert!(ctx.update_source("a", input).is_ok());
+        let output = ctx.update_source("b", input);
+        assert_eq!(output.unwrap(), expect);
+        // Removing a source does not relabel the others.
+        ctx.remove_source("a");
+        assert!(ctx.result().contains("source=\"b\""));
+        assert!(ctx.non_aggregatable_families().is_empty());
+
+        let mut ctx = Context::new(input);
+        ctx.set_aggregation(Aggregation::sum_without(["instance"]));
+        assert!(ctx.run().is_ok());
+        let output = ctx.combine_with_prefix(input, "").unwrap();
+        assert!(!output.contains("quantile"));
+        assert_eq!(ctx.non_aggregatable_families(), ["rpc_duration_seconds"]);
+    }
+}
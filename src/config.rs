@@ -0,0 +1,835 @@
+//! Module containing declarative configuration of merge pipelines.
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::aggregate::{Aggregation, BucketMerge, Operator, QuantilePolicy};
+use crate::filter::Filter;
+use crate::name::{is_valid_metric_name, NamePolicy};
+use crate::promerge::{Context, Kind};
+use crate::relabel::{is_valid_label_name, Action, RelabelConfig};
+use crate::selector::Selector;
+use crate::server::{ServeConfig, Target};
+use crate::staleness::{StaleAction, Staleness};
+use crate::timestamp::TimestampPolicy;
+
+/// Error returned when a configuration can not be loaded.
+#[derive(Debug)]
+pub enum Error {
+    Io(std::io::Error),
+    /// The file extension is none of `json`, `yaml`, `yml` or `toml`.
+    Format(String),
+    /// The value at `key`, e.g. `sources[1].prefix`, is invalid.
+    Invalid {
+        key: String,
+        message: String,
+    },
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "failed to read config: {}", err),
+            Error::Format(path) => write!(f, "unsupported config format {:?}", path),
+            Error::Invalid { key, message } if key.is_empty() => write!(f, "{}", message),
+            Error::Invalid { key, message } => write!(f, "{}: {}", key, message),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+fn invalid<K: Into<String>, M: std::fmt::Display>(key: K, message: M) -> Error {
+    Error::Invalid {
+        key: key.into(),
+        message: message.to_string(),
+    }
+}
+
+/// OutputFormat selects the exposition format of the merged output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    /// Prometheus text format 0.0.4.
+    #[default]
+    Text,
+    /// OpenMetrics text format 1.0.0. Families of the same
+    /// name are merged, other comments are dropped and sample
+    /// timestamps are converted to seconds.
+    OpenMetrics,
+}
+
+impl OutputFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            OutputFormat::Text => "text/plain; version=0.0.4",
+            OutputFormat::OpenMetrics => {
+                "application/openmetrics-text; version=1.0.0; charset=utf-8"
+            }
+        }
+    }
+
+    /// Converts the merged `output` of `Context` to this format.
+    pub fn render<'o>(&self, output: &'o str) -> Cow<'o, str> {
+        match self {
+            OutputFormat::Text => Cow::Borrowed(output),
+            OutputFormat::OpenMetrics => Cow::Owned(openmetrics(output)),
+        }
+    }
+}
+
+/// Family is a metric family of the OpenMetrics output.
+#[derive(Default)]
+struct Family<'o> {
+    name: String,
+    help: Option<&'o str>,
+    kind: Option<&'o str>,
+    samples: Vec<String>,
+}
+
+/// Converts the Prometheus text `output` to OpenMetrics, where
+/// counter families are named without `_total` while their
+/// samples carry it.
+fn openmetrics(output: &str) -> String {
+    let mut families: Vec<Family> = Vec::new();
+    let mut index: BTreeMap<String, usize> = BTreeMap::new();
+    let mut lines = output.lines().filter(|l| !l.trim().is_empty()).peekable();
+    while lines.peek().is_some() {
+        let mut help = None;
+        let mut kind = None;
+        while let Some(line) = lines.next_if(|l| l.starts_with('#')) {
+            let mut parts = line.splitn(4, ' ');
+            match (parts.next(), parts.next(), parts.next()) {
+                (Some("#"), Some("HELP"), Some(name)) => help = Some((name, parts.next())),
+                (Some("#"), Some("TYPE"), Some(name)) => kind = Some((name, parts.next())),
+                _ => {}
+            }
+        }
+        let first = lines.peek().map(|l| split_sample(l).0);
+        let Some(name) = kind.map(|k| k.0).or(help.map(|h| h.0)).or(first) else {
+            continue;
+        };
+        let kind = kind.and_then(|k| k.1).map(|k| match k {
+            "untyped" => "unknown",
+            kind => kind,
+        });
+        let name = match kind {
+            Some("counter") => name.strip_suffix("_total").unwrap_or(name),
+            _ => name,
+        };
+        let idx = *index.entry(name.into()).or_insert_with(|| {
+            families.push(Family {
+                name: name.into(),
+                ..Default::default()
+            });
+            families.len() - 1
+        });
+        let family = &mut families[idx];
+        family.help = family.help.or(help.and_then(|h| h.1));
+        family.kind = family.kind.or(kind);
+        while let Some(line) = lines.next_if(|l| !l.starts_with('#')) {
+            let (metric, rest) = split_sample(line);
+            let mut sample = String::with_capacity(line.len() + 6);
+            sample.push_str(metric);
+            if family.kind == Some("counter") && metric == family.name {
+                sample.push_str("_total");
+            }
+            let (labels, rest) = split_labels(rest);
+            sample.push_str(labels);
+            let mut parts = rest.split_whitespace();
+            if let Some(value) = parts.next() {
+                sample.push(' ');
+                sample.push_str(value);
+            }
+            if let Some(timestamp) = parts.next() {
+                sample.push(' ');
+                match timestamp.parse::<i64>() {
+                    Ok(ms) => {
+                        let sign = if ms < 0 { "-" } else { "" };
+                        let ms = ms.unsigned_abs();
+                        sample.push_str(&format!("{}{}.{:03}", sign, ms / 1000, ms % 1000));
+                    }
+                    Err(_) => sample.push_str(timestamp),
+                }
+            }
+            family.samples.push(sample);
+        }
+    }
+    let mut buffer = String::with_capacity(output.len() + 6);
+    for family in families {
+        if let Some(help) = family.help {
+            let help = help.replace('"', "\\\"");
+            buffer.push_str(&format!("# HELP {} {}\n", family.name, help));
+        }
+        if let Some(kind) = family.kind {
+            buffer.push_str(&format!("# TYPE {} {}\n", family.name, kind));
+        }
+        for sample in family.samples {
+            buffer.push_str(&sample);
+            buffer.push('\n');
+        }
+    }
+    buffer.push_str("# EOF\n");
+    buffer
+}
+
+/// Splits a sample `line` after its metric name.
+fn split_sample(line: &str) -> (&str, &str) {
+    line.split_at(line.find(['{', ' ']).unwrap_or(line.len()))
+}
+
+/// Splits the label set at the start of `rest`, if any, from
+/// the value and timestamp.
+fn split_labels(rest: &str) -> (&str, &str) {
+    if !rest.starts_with('{') {
+        return ("", rest);
+    }
+    let mut quoted = false;
+    let mut escaped = false;
+    for (idx, c) in rest.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' => quoted = !quoted,
+            '}' if !quoted => return rest.split_at(idx + 1),
+            _ => {}
+        }
+    }
+    (rest, "")
+}
+
+/// SourceConfig is an input of the pipeline, either scraped
+/// from `url` or read from `path`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SourceConfig {
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub url: Option<String>,
+    #[serde(default)]
+    pub path: Option<String>,
+    #[serde(default)]
+    pub prefix: String,
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+}
+
+impl SourceConfig {
+    /// Returns the name of the source, defaulting to its url or path.
+    pub fn name(&self) -> &str {
+        self.name
+            .as_deref()
+            .or(self.url.as_deref())
+            .or(self.path.as_deref())
+            .unwrap_or("")
+    }
+
+    pub fn pairs(&self) -> Vec<(String, String)> {
+        self.labels
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect()
+    }
+}
+
+/// RelabelRule mirrors a Prometheus `relabel_configs` entry,
+/// e.g. `{action: labeldrop, regex: "pod|instance"}`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RelabelRule {
+    #[serde(default)]
+    pub action: Option<String>,
+    #[serde(default)]
+    pub source_labels: Vec<String>,
+    #[serde(default)]
+    pub separator: Option<String>,
+    #[serde(default)]
+    pub regex: Option<String>,
+    #[serde(default)]
+    pub modulus: Option<u64>,
+    #[serde(default)]
+    pub target_label: Option<String>,
+    #[serde(default)]
+    pub replacement: Option<String>,
+}
+
+/// FilterConfig lists glob and regex patterns of family names.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FilterConfig {
+    #[serde(default)]
+    pub allow: Vec<String>,
+    #[serde(default)]
+    pub deny: Vec<String>,
+    #[serde(default)]
+    pub allow_regex: Vec<String>,
+    #[serde(default)]
+    pub deny_regex: Vec<String>,
+}
+
+/// AggregationConfig decides how series colliding across
+/// sources are reduced, see `Aggregation`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AggregationConfig {
+    #[serde(default)]
+    pub without: Vec<String>,
+    /// Operator per kind, e.g. `gauge: max`.
+    #[serde(default)]
+    pub kinds: BTreeMap<String, String>,
+    /// Operator per family name, overriding `kinds`.
+    #[serde(default)]
+    pub families: BTreeMap<String, String>,
+    #[serde(default)]
+    pub bucket_merge: Option<String>,
+    /// One of `drop`, `report` or `source`.
+    #[serde(default)]
+    pub quantiles: Option<String>,
+    /// Label holding the source name, or the position of an
+    /// unnamed source, when `quantiles` is `source`.
+    #[serde(default)]
+    pub quantile_label: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StalenessConfig {
+    pub ttl_secs: u64,
+    #[serde(default)]
+    pub action: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OutputConfig {
+    #[serde(default)]
+    pub format: OutputFormat,
+    /// File written by the merge command instead of stdout.
+    #[serde(default)]
+    pub path: Option<String>,
+}
+
+/// Config describes a whole merge pipeline and is loaded from
+/// JSON, YAML or TOML:
+///
+/// ```yaml
+/// listen: 0.0.0.0:9100
+/// sources:
+///   - url: http://10.0.0.1:9100/metrics
+///     prefix: node_
+///     labels: {zone: eu}
+/// relabel_configs:
+///   - action: labeldrop
+///     regex: pod
+/// filter:
+///   deny: ["go_*"]
+/// aggregation:
+///   without: [instance]
+///   kinds: {gauge: max}
+/// output:
+///   format: openmetrics
+/// ```
+///
+/// Loading only checks the shape of the document, `context`
+/// and `serve_config` validate the values. Errors name the
+/// offending key.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default = "crate::server::default_listen")]
+    pub listen: String,
+    #[serde(default = "crate::server::default_interval")]
+    pub interval_secs: u64,
+    #[serde(default = "crate::server::default_timeout")]
+    pub timeout_secs: u64,
+    #[serde(default, alias = "targets")]
+    pub sources: Vec<SourceConfig>,
+    #[serde(default)]
+    pub relabel_configs: Vec<RelabelRule>,
+    #[serde(default)]
+    pub filter: Option<FilterConfig>,
+    #[serde(default)]
+    pub selector: Option<String>,
+    #[serde(default)]
+    pub aggregation: Option<AggregationConfig>,
+    #[serde(default)]
+    pub name_policy: Option<String>,
+    #[serde(default)]
+    pub timestamps: Option<String>,
+    #[serde(default)]
+    pub staleness: Option<StalenessConfig>,
+    #[serde(default)]
+    pub output: OutputConfig,
+}
+
+/// Returns the value of `choices` named `value`.
+fn choose<T: Clone>(key: &str, value: &str, choices: &[(&str, T)]) -> Result<T, Error> {
+    match choices.iter().find(|(name, _)| *name == value) {
+        Some((_, choice)) => Ok(choice.clone()),
+        None => {
+            let names: Vec<&str> = choices.iter().map(|(name, _)| *name).collect();
+            Err(invalid(
+                key,
+                format!(
+                    "unknown value {:?}, expected one of {}",
+                    value,
+                    names.join(", ")
+                ),
+            ))
+        }
+    }
+}
+
+fn path_error<E: std::fmt::Display>(err: serde_path_to_error::Error<E>) -> Error {
+    let key = err.path().to_string();
+    let key = if key == "." { String::new() } else { key };
+    invalid(key, err.inner())
+}
+
+const OPERATORS: &[(&str, Operator)] = &[
+    ("sum", Operator::Sum),
+    ("min", Operator::Min),
+    ("max", Operator::Max),
+    ("avg", Operator::Avg),
+    ("last", Operator::Last),
+    ("count", Operator::Count),
+];
+
+impl Config {
+    pub fn from_json(input: &str) -> Result<Self, Error> {
+        let mut deserializer = serde_json::Deserializer::from_str(input);
+        serde_path_to_error::deserialize(&mut deserializer).map_err(path_error)
+    }
+
+    pub fn from_yaml(input: &str) -> Result<Self, Error> {
+        serde_path_to_error::deserialize(serde_yaml_ng::Deserializer::from_str(input))
+            .map_err(path_error)
+    }
+
+    pub fn from_toml(input: &str) -> Result<Self, Error> {
+        serde_path_to_error::deserialize(toml::Deserializer::new(input)).map_err(path_error)
+    }
+
+    /// Reads the configuration at `path`, choosing the format
+    /// by its extension.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let input = std::fs::read_to_string(path)?;
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("json") => Self::from_json(&input),
+            Some("yaml" | "yml") => Self::from_yaml(&input),
+            Some("toml") => Self::from_toml(&input),
+            _ => Err(Error::Format(path.display().to_string())),
+        }
+    }
+
+    fn name_policy(&self) -> Result<NamePolicy, Error> {
+        match &self.name_policy {
+            Some(policy) => choose(
+                "name_policy",
+                policy,
+                &[
+                    ("strict", NamePolicy::Strict),
+                    ("sanitize", NamePolicy::Sanitize),
+                ],
+            ),
+            None => Ok(NamePolicy::default()),
+        }
+    }
+
+    fn validate_durations(&self) -> Result<(), Error> {
+        for (key, secs) in [
+            ("interval_secs", self.interval_secs),
+            ("timeout_secs", self.timeout_secs),
+        ] {
+            if secs == 0 {
+                return Err(invalid(key, "expected a positive number of seconds"));
+            }
+        }
+        Ok(())
+    }
+
+    fn validate_sources(&self, policy: NamePolicy) -> Result<(), Error> {
+        let mut names: Vec<&str> = Vec::with_capacity(self.sources.len());
+        for (idx, source) in self.sources.iter().enumerate() {
+            let key = format!("sources[{}]", idx);
+            if source.url.is_some() == source.path.is_some() {
+                return Err(invalid(key, "expected exactly one of url or path"));
+            }
+            if names.contains(&source.name()) {
+                return Err(invalid(
+                    format!("{}.name", key),
+                    format!("duplicate source {:?}", source.name()),
+                ));
+            }
+            names.push(source.name());
+            if policy == NamePolicy::Strict
+                && !source.prefix.is_empty()
+                && !is_valid_metric_name(&source.prefix)
+            {
+                return Err(invalid(
+                    format!("{}.prefix", key),
+                    format!("invalid prefix {:?}", source.prefix),
+                ));
+            }
+            if let Some(label) = source.labels.keys().find(|l| !is_valid_label_name(l)) {
+                return Err(invalid(
+                    format!("{}.labels.{}", key, label),
+                    format!("invalid label name {:?}", label),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn relabel_configs(&self) -> Result<Vec<RelabelConfig>, Error> {
+        let mut configs = Vec::with_capacity(self.relabel_configs.len());
+        for (idx, rule) in self.relabel_configs.iter().enumerate() {
+            let key = format!("relabel_configs[{}]", idx);
+            let action = choose(
+                &format!("{}.action", key),
+                rule.action.as_deref().unwrap_or("replace"),
+                &[
+                    ("replace", Action::Replace),
+                    ("keep", Action::Keep),
+                    ("drop", Action::Drop),
+                    ("labelmap", Action::LabelMap),
+                    ("labeldrop", Action::LabelDrop),
+                    ("labelkeep", Action::LabelKeep),
+                    ("hashmod", Action::HashMod),
+                    ("lowercase", Action::Lowercase),
+                    ("uppercase", Action::Uppercase),
+                    ("keepequal", Action::KeepEqual),
+                    ("dropequal", Action::DropEqual),
+                ],
+            )?;
+            let needs_target = matches!(
+                action,
+                Action::Replace
+                    | Action::HashMod
+                    | Action::Lowercase
+                    | Action::Uppercase
+                    | Action::KeepEqual
+                    | Action::DropEqual
+            );
+            if needs_target && rule.target_label.as_deref().unwrap_or("").is_empty() {
+                return Err(invalid(
+                    format!("{}.target_label", key),
+                    "required by this action",
+                ));
+            }
+            if action == Action::HashMod && rule.modulus.unwrap_or(0) == 0 {
+                return Err(invalid(
+                    format!("{}.modulus", key),
+                    "hashmod requires a positive modulus",
+                ));
+            }
+            let mut config = RelabelConfig::new(action).with_source_labels(&rule.source_labels);
+            if let Some(separator) = &rule.separator {
+                config = config.with_separator(separator);
+            }
+            if let Some(regex) = &rule.regex {
+                config = config
+                    .with_regex(regex)
+                    .map_err(|err| invalid(format!("{}.regex", key), err))?;
+            }
+            if let Some(modulus) = rule.modulus {
+                config = config.with_modulus(modulus);
+            }
+            if let Some(target) = &rule.target_label {
+                config = config.with_target_label(target);
+            }
+            if let Some(replacement) = &rule.replacement {
+                config = config.with_replacement(replacement);
+            }
+            configs.push(config);
+        }
+        Ok(configs)
+    }
+
+    fn filter(&self, filter: &FilterConfig) -> Result<Filter, Error> {
+        let mut result = Filter::new();
+        for pattern in &filter.allow {
+            result = result.allow_glob(pattern);
+        }
+        for pattern in &filter.deny {
+            result = result.deny_glob(pattern);
+        }
+        for (idx, pattern) in filter.allow_regex.iter().enumerate() {
+            result = result
+                .allow_regex(pattern)
+                .map_err(|err| invalid(format!("filter.allow_regex[{}]", idx), err))?;
+        }
+        for (idx, pattern) in filter.deny_regex.iter().enumerate() {
+            result = result
+                .deny_regex(pattern)
+                .map_err(|err| invalid(format!("filter.deny_regex[{}]", idx), err))?;
+        }
+        Ok(result)
+    }
+
+    fn aggregation(&self, config: &AggregationConfig) -> Result<Aggregation, Error> {
+        let mut aggregation = Aggregation::sum_without(&config.without);
+        for (kind, operator) in &config.kinds {
+            let key = format!("aggregation.kinds.{}", kind);
+            let kind = choose(
+                &key,
+                kind,
+                &[
+                    ("counter", Kind::Counter),
+                    ("gauge", Kind::Gauge),
+                    ("untyped", Kind::Untyped),
+                ],
+            )?;
+            aggregation = aggregation.with_kind(kind, choose(&key, operator, OPERATORS)?);
+        }
+        for (family, operator) in &config.families {
+            let key = format!("aggregation.families.{}", family);
+            aggregation = aggregation.with_family(family, choose(&key, operator, OPERATORS)?);
+        }
+        if let Some(buckets) = &config.bucket_merge {
+            aggregation = aggregation.with_bucket_merge(choose(
+                "aggregation.bucket_merge",
+                buckets,
+                &[
+                    ("conservative", BucketMerge::Conservative),
+                    ("interpolate", BucketMerge::Interpolate),
+                ],
+            )?);
+        }
+        if let Some(quantiles) = &config.quantiles {
+            let label = config.quantile_label.clone().unwrap_or_default();
+            let policy = choose(
+                "aggregation.quantiles",
+                quantiles,
+                &[
+                    ("drop", QuantilePolicy::Drop),
+                    ("report", QuantilePolicy::Report),
+                    ("source", QuantilePolicy::Source(label.clone())),
+                ],
+            )?;
+            if matches!(policy, QuantilePolicy::Source(_)) && !is_valid_label_name(&label) {
+                return Err(invalid(
+                    "aggregation.quantile_label",
+                    format!("invalid label name {:?}", label),
+                ));
+            }
+            aggregation = aggregation.with_quantile_policy(policy);
+        }
+        Ok(aggregation)
+    }
+
+    /// Validates the configuration and builds a `Context`
+    /// running its pipeline, to which sources are merged.
+    pub fn context(&self) -> Result<Context<'static>, Error> {
+        let policy = self.name_policy()?;
+        self.validate_durations()?;
+        self.validate_sources(policy)?;
+        let mut ctx = Context::new("");
+        ctx.set_name_policy(policy);
+        ctx.set_relabel_configs(self.relabel_configs()?);
+        if let Some(filter) = &self.filter {
+            ctx.set_filter(self.filter(filter)?);
+        }
+        if let Some(selector) = &self.selector {
+            ctx.set_selector(Selector::parse(selector).map_err(|err| invalid("selector", err))?);
+        }
+        if let Some(aggregation) = &self.aggregation {
+            ctx.set_aggregation(self.aggregation(aggregation)?);
+        }
+        if let Some(timestamps) = &self.timestamps {
+            ctx.set_timestamp_policy(choose(
+                "timestamps",
+                timestamps,
+                &[
+                    ("keep", TimestampPolicy::Keep),
+                    ("strip", TimestampPolicy::Strip),
+                    ("now", TimestampPolicy::Now),
+                ],
+            )?);
+        }
+        if let Some(staleness) = &self.staleness {
+            let action = choose(
+                "staleness.action",
+                staleness.action.as_deref().unwrap_or("omit"),
+                &[
+                    ("omit", StaleAction::Omit),
+                    ("label", StaleAction::Label),
+                    ("nan", StaleAction::Nan),
+                ],
+            )?;
+            ctx.set_staleness(
+                Staleness::new(Duration::from_secs(staleness.ttl_secs)).with_action(action),
+            );
+        }
+        Ok(ctx)
+    }
+
+    /// Returns the configuration of `promerge serve`, which
+    /// only scrapes sources with an `url`.
+    pub fn serve_config(&self) -> Result<ServeConfig, Error> {
+        self.validate_durations()?;
+        let mut targets = Vec::with_capacity(self.sources.len());
+        for (idx, source) in self.sources.iter().enumerate() {
+            let Some(url) = &source.url else {
+                return Err(invalid(
+                    format!("sources[{}].url", idx),
+                    "required by serve",
+                ));
+            };
+            targets.push(Target {
+                name: source.name.clone(),
+                url: url.clone(),
+                prefix: source.prefix.clone(),
+                labels: source.labels.clone(),
+            });
+        }
+        Ok(ServeConfig {
+            listen: self.listen.clone(),
+            interval_secs: self.interval_secs,
+            timeout_secs: self.timeout_secs,
+            targets,
+            format: self.output.format,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_config() {
+        let yaml = r#"
+sources:
+  - path: a.prom
+    prefix: a_
+    labels: {zone: x}
+  - path: b.prom
+relabel_configs:
+  - action: labeldrop
+    regex: pod
+filter:
+  deny: ["go_*"]
+aggregation:
+  without: [zone]
+  kinds: {gauge: max}
+output:
+  format: openmetrics
+"#;
+        let toml = r#"
+[[sources]]
+path = "a.prom"
+prefix = "a_"
+labels = { zone = "x" }
+
+[[sources]]
+path = "b.prom"
+
+[[relabel_configs]]
+action = "labeldrop"
+regex = "pod"
+
+[filter]
+deny = ["go_*"]
+
+[aggregation]
+without = ["zone"]
+kinds = { gauge = "max" }
+
+[output]
+format = "openmetrics"
+"#;
+        for config in [Config::from_yaml(yaml), Config::from_toml(toml)] {
+            let config = config.unwrap();
+            let mut ctx = config.context().unwrap();
+            ctx.combine_with_prefix_and_pairs(
+                "# TYPE up gauge\nup{pod=\"p\"} 1\n# TYPE go_goroutines gauge\ngo_goroutines 7\n",
+                &config.sources[0].pairs(),
+                config.sources[0].prefix.as_str(),
+            )
+            .unwrap();
+            ctx.combine_with_prefix_and_pairs("# TYPE up gauge\nup 0\n", &[], "a_")
+                .unwrap();
+            assert_eq!(
+                config.output.format.render(ctx.result()),
+                "# TYPE a_up gauge\na_up 1\n# EOF\n"
+            );
+            let err = config.serve_config().unwrap_err();
+            assert_eq!(err.to_string(), "sources[0].url: required by serve");
+        }
+        let output = "# A comment\n# HELP c_total Requests.\n# TYPE c_total counter\n\
+            c_total{a=\"}\"} 1 1500\n\n# TYPE d counter\nd 1 -1500\n\n\
+            # TYPE c_total counter\nc_total 2\n";
+        assert_eq!(
+            OutputFormat::OpenMetrics.render(output),
+            "# HELP c Requests.\n# TYPE c counter\nc_total{a=\"}\"} 1 1.500\nc_total 2\n\
+            # TYPE d counter\nd_total 1 -1.500\n# EOF\n"
+        );
+
+        for (input, key) in [
+            (
+                "sources:\n  - path: a\n    prefix: 1a\n",
+                "sources[0].prefix",
+            ),
+            ("sources:\n  - path: a\n    url: http://a\n", "sources[0]"),
+            (
+                "sources:\n  - path: a\n    labels: {a-b: c}\n",
+                "sources[0].labels.a-b",
+            ),
+            (
+                "relabel_configs:\n  - action: drop\n  - action: hashmod\n",
+                "relabel_configs[1].target_label",
+            ),
+            (
+                "relabel_configs:\n  - action: keep\n    regex: \"(\"\n",
+                "relabel_configs[0].regex",
+            ),
+            (
+                "aggregation:\n  kinds: {gauge: median}\n",
+                "aggregation.kinds.gauge",
+            ),
+            ("name_policy: loose\n", "name_policy"),
+            ("timeout_secs: 0\n", "timeout_secs"),
+        ] {
+            match Config::from_yaml(input).unwrap().context() {
+                Err(Error::Invalid { key: k, .. }) => assert_eq!(k, key),
+                other => panic!("unexpected result for {:?}: {:?}", input, other.is_ok()),
+            }
+        }
+        let config = Config::from_yaml("interval_secs: 0\nsources:\n  - url: http://a\n");
+        match config.unwrap().serve_config() {
+            Err(Error::Invalid { key, .. }) => assert_eq!(key, "interval_secs"),
+            other => panic!("unexpected result: {:?}", other.is_ok()),
+        }
+        for (err, key) in [
+            (
+                Config::from_yaml("sources:\n  - path: a\n    prefx: a_\n"),
+                "sources[0].prefx",
+            ),
+            (
+                Config::from_toml("[output]\nformat = \"xml\"\n"),
+                "output.format",
+            ),
+            (
+                Config::from_json(r#"{"sources": [{"url": 1}]}"#),
+                "sources[0].url",
+            ),
+        ] {
+            match err {
+                Err(Error::Invalid { key: k, .. }) => assert_eq!(k, key),
+                other => panic!("unexpected result: {:?}", other),
+            }
+        }
+    }
+}
@@ -0,0 +1,104 @@
+//! Module containing allow and deny filtering of metric families.
+use regex::Regex;
+
+use crate::relabel::anchored;
+
+/// Filter decides which metric families are kept while parsing.
+///
+/// Patterns are matched against the whole family name, before
+/// any prefix is added. A family is kept when it matches one of
+/// the allow patterns, or there are none, and matches none of
+/// the deny patterns.
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    allow: Vec<Regex>,
+    deny: Vec<Regex>,
+}
+
+impl Filter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allows families matching the glob `pattern`,
+    /// where `*` matches any run of characters and `?`
+    /// a single one.
+    pub fn allow_glob(mut self, pattern: &str) -> Self {
+        self.allow.push(glob(pattern));
+        self
+    }
+
+    /// Allows families matching the regular expression `pattern`.
+    pub fn allow_regex(mut self, pattern: &str) -> Result<Self, regex::Error> {
+        self.allow.push(anchored(pattern)?);
+        Ok(self)
+    }
+
+    /// Denies families matching the glob `pattern`.
+    pub fn deny_glob(mut self, pattern: &str) -> Self {
+        self.deny.push(glob(pattern));
+        self
+    }
+
+    /// Denies families matching the regular expression `pattern`.
+    pub fn deny_regex(mut self, pattern: &str) -> Result<Self, regex::Error> {
+        self.deny.push(anchored(pattern)?);
+        Ok(self)
+    }
+
+    pub fn is_allowed(&self, name: &str) -> bool {
+        (self.allow.is_empty() || self.allow.iter().any(|r| r.is_match(name)))
+            && !self.deny.iter().any(|r| r.is_match(name))
+    }
+}
+
+fn glob(pattern: &str) -> Regex {
+    let mut buffer = String::with_capacity(pattern.len() + 2);
+    buffer.push('^');
+    for c in pattern.chars() {
+        match c {
+            '*' => buffer.push_str(".*"),
+            '?' => buffer.push('.'),
+            c => buffer.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+        }
+    }
+    buffer.push('$');
+    Regex::new(&buffer).unwrap()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::promerge::Context;
+
+    #[test]
+    fn test_filter() {
+        let input = r#"# HELP go_gc_duration_seconds A summary of the GC invocation durations.
+# TYPE go_gc_duration_seconds summary
+go_gc_duration_seconds{quantile="0.5"} 0.01
+go_gc_duration_seconds_sum 1.5
+go_gc_duration_seconds_count 20
+# HELP go_goroutines Number of goroutines that currently exist.
+# TYPE go_goroutines gauge
+go_goroutines 42
+# HELP http_requests_total The total number of HTTP requests.
+# TYPE http_requests_total counter
+http_requests_total{method="post",code="200"} 1027
+"#;
+        let expect = r#"# HELP prefix_go_goroutines Number of goroutines that currently exist.
+# TYPE prefix_go_goroutines gauge
+prefix_go_goroutines 42
+
+"#;
+        let filter = Filter::new()
+            .allow_glob("go_*")
+            .deny_regex("go_gc_.+")
+            .unwrap();
+        assert!(filter.is_allowed("go_goroutines"));
+        assert!(!filter.is_allowed("http_requests_total"));
+
+        let mut ctx = Context::with_prefix(input, "prefix_");
+        ctx.set_filter(filter);
+        assert_eq!(ctx.run().unwrap(), expect);
+    }
+}
@@ -0,0 +1,204 @@
+//! Module containing interning of label names and values.
+use std::collections::HashSet;
+use std::fmt;
+use std::ops::Deref;
+use std::sync::{Arc, Mutex};
+
+use crate::promerge::Value;
+
+/// Label is a label name or value, either borrowed from the
+/// input, owned, or shared through an `Interner`.
+#[derive(Debug, Clone)]
+pub enum Label<'a> {
+    Borrowed(&'a str),
+    Owned(String),
+    Shared(Arc<str>),
+}
+
+impl Label<'_> {
+    /// Returns a label that does not borrow from the input.
+    /// Shared labels stay shared.
+    pub fn into_owned(self) -> Label<'static> {
+        match self {
+            Label::Borrowed(value) => Label::Owned(value.into()),
+            Label::Owned(value) => Label::Owned(value),
+            Label::Shared(value) => Label::Shared(value),
+        }
+    }
+}
+
+impl Deref for Label<'_> {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        match self {
+            Label::Borrowed(value) => value,
+            Label::Owned(value) => value,
+            Label::Shared(value) => value,
+        }
+    }
+}
+
+impl AsRef<str> for Label<'_> {
+    fn as_ref(&self) -> &str {
+        self
+    }
+}
+
+impl fmt::Display for Label<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self)
+    }
+}
+
+impl PartialEq for Label<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        **self == **other
+    }
+}
+
+impl Eq for Label<'_> {}
+
+impl PartialEq<str> for Label<'_> {
+    fn eq(&self, other: &str) -> bool {
+        &**self == other
+    }
+}
+
+impl PartialEq<&str> for Label<'_> {
+    fn eq(&self, other: &&str) -> bool {
+        &**self == *other
+    }
+}
+
+impl PartialEq<Label<'_>> for String {
+    fn eq(&self, other: &Label<'_>) -> bool {
+        self == &**other
+    }
+}
+
+impl<'a> From<&'a str> for Label<'a> {
+    fn from(value: &'a str) -> Self {
+        Label::Borrowed(value)
+    }
+}
+
+impl From<String> for Label<'_> {
+    fn from(value: String) -> Self {
+        Label::Owned(value)
+    }
+}
+
+impl From<Arc<str>> for Label<'_> {
+    fn from(value: Arc<str>) -> Self {
+        Label::Shared(value)
+    }
+}
+
+/// Interner stores each distinct label name and value once,
+/// so that series of all merged inputs can share them instead
+/// of owning a copy.
+///
+/// Cloning it gives another handle to the same strings, e.g.
+/// to share them between the contexts of a `Registry`. Strings
+/// no series uses any more are dropped by `collect`.
+#[derive(Debug, Clone, Default)]
+pub struct Interner {
+    strings: Arc<Mutex<HashSet<Arc<str>>>>,
+}
+
+impl Interner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the interned copy of `value`.
+    pub fn intern(&self, value: &str) -> Arc<str> {
+        let mut strings = self.strings.lock().unwrap();
+        match strings.get(value) {
+            Some(interned) => interned.clone(),
+            None => {
+                let interned: Arc<str> = value.into();
+                strings.insert(interned.clone());
+                interned
+            }
+        }
+    }
+
+    /// Drops the strings which are only held by the interner.
+    pub fn collect(&self) {
+        let mut strings = self.strings.lock().unwrap();
+        strings.retain(|s| Arc::strong_count(s) > 1);
+    }
+
+    /// Returns the number of distinct strings.
+    pub fn len(&self) -> usize {
+        self.strings.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn share(&self, label: &mut Label<'_>) {
+        if let Label::Owned(owned) = label {
+            *label = Label::Shared(self.intern(owned));
+        }
+    }
+
+    /// Replaces the owned label names and values of every
+    /// series of `value` by their interned copies.
+    pub(crate) fn intern_value(&self, value: &mut Value<'_>) {
+        let segments = [&mut value.sum, &mut value.count].into_iter().flatten();
+        let pairs = value.pairs.iter_mut().chain(segments.map(|s| &mut s.pairs));
+        for pairs in pairs {
+            for (key, value) in pairs.iter_mut() {
+                self.share(key);
+                self.share(value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::promerge::Context;
+
+    #[test]
+    fn test_interner() {
+        let input = r#"# HELP http_requests_total The total number of HTTP requests.
+# TYPE http_requests_total counter
+http_requests_total{method="post",code="200"} 1027
+http_requests_total{method="get",code="200"} 3
+"#;
+        let pairs = [("region".to_string(), "eu-west".to_string())];
+        let interner = Interner::new();
+        let mut ctx = Context::new("");
+        ctx.set_interner(interner.clone());
+        // families are only shared when they are kept.
+        ctx.set_keep_values(true);
+        let mut ctx = ctx.into_owned();
+        ctx.update_source_owned("a", input, &pairs, "a_").unwrap();
+        ctx.combine_reader(input.as_bytes(), &pairs, "b_").unwrap();
+
+        let labels: Vec<&str> = ctx
+            .values()
+            .unwrap()
+            .flat_map(|v| v.pairs.iter().flatten())
+            .filter(|(k, _)| k == "region" || k == "method")
+            .map(|(_, v)| v.as_ref())
+            .collect();
+        assert_eq!(labels.len(), 8);
+        let region = labels.iter().filter(|v| **v == "eu-west");
+        assert!(region.clone().all(|v| std::ptr::eq(*v, labels[1])));
+        assert_eq!(interner.len(), 7);
+
+        // strings only used by the replaced source are dropped.
+        let input = input.replace("post", "put");
+        ctx.update_source_owned("a", &input, &pairs, "a_").unwrap();
+        assert_eq!(interner.len(), 8);
+        ctx.remove_source("a");
+        assert_eq!(interner.len(), 7);
+    }
+}
@@ -0,0 +1,205 @@
+//! Module containing lightweight rewriting of label keys.
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+use crate::aggregate::{format_float, parse_float};
+use crate::intern::Label;
+use crate::promerge::{Error, Kind, Segment, Value};
+use crate::relabel::is_valid_label_name;
+
+type LabelPair<'a> = (Label<'a>, Label<'a>);
+
+/// LabelRewrite removes labels and renames label keys of a
+/// family, then merges series which became identical.
+///
+/// A renamed label replaces a label that already had its new
+/// key, and of labels renamed to the same key the one of the
+/// first rule wins. Merged counter and histogram series are
+/// summed, for other kinds the last series wins. Merged series carry no
+/// timestamp. Series of different inputs are not merged, use
+/// `Aggregation` for that.
+#[derive(Debug, Clone, Default)]
+pub struct LabelRewrite {
+    drop: Vec<String>,
+    rename: Vec<(String, String)>,
+}
+
+impl LabelRewrite {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Removes the label `name`, e.g. a high-cardinality `path`.
+    pub fn drop<S: Into<String>>(mut self, name: S) -> Self {
+        self.drop.push(name.into());
+        self
+    }
+
+    /// Renames the label key `from` to `to`, failing if `to` is
+    /// not a valid label name.
+    pub fn rename<F, T>(mut self, from: F, to: T) -> Result<Self, Error>
+    where
+        F: Into<String>,
+        T: Into<String>,
+    {
+        let to = to.into();
+        if !is_valid_label_name(&to) {
+            return Err(Error::InvalidName {
+                what: "label name",
+                name: to,
+            });
+        }
+        self.rename.push((from.into(), to));
+        Ok(self)
+    }
+
+    fn rewrite(&self, pairs: &mut Vec<LabelPair<'_>>) {
+        pairs.retain(|(k, _)| !self.drop.iter().any(|d| d == k));
+        // Ranks labels by the rule renaming them, unrenamed
+        // labels last, to keep one label per key.
+        let mut ranks = Vec::with_capacity(pairs.len());
+        for (idx, (key, _)) in pairs.iter_mut().enumerate() {
+            match self.rename.iter().position(|(from, _)| from == key) {
+                Some(rule) => {
+                    *key = self.rename[rule].1.clone().into();
+                    ranks.push((rule, idx));
+                }
+                None => ranks.push((usize::MAX, idx)),
+            }
+        }
+        let keep: Vec<bool> = ranks
+            .iter()
+            .map(|rank| {
+                !ranks
+                    .iter()
+                    .any(|other| other < rank && pairs[other.1].0 == pairs[rank.1].0)
+            })
+            .collect();
+        let mut keep = keep.into_iter();
+        pairs.retain(|_| keep.next().unwrap_or(true));
+    }
+
+    /// Rewrites the labels of every series of `value`.
+    pub fn apply(&self, value: &mut Value<'_>) {
+        let sum = matches!(value.kind(), Kind::Counter | Kind::Histogram);
+        let pairs = std::mem::take(&mut value.pairs);
+        let samples = std::mem::take(&mut value.values);
+        let mut index: HashMap<Vec<(String, String)>, usize> = HashMap::new();
+        for (mut pairs, sample) in pairs.into_iter().zip(samples) {
+            self.rewrite(&mut pairs);
+            let key = key(&pairs);
+            match index.get(&key) {
+                Some(&idx) => {
+                    let merged = if sum {
+                        let total = parse_float(&value.values[idx].0) + parse_float(&sample.0);
+                        Cow::Owned(format_float(total))
+                    } else {
+                        sample.0
+                    };
+                    value.values[idx] = (merged, None);
+                }
+                None => {
+                    if pairs.is_empty() {
+                        pairs.push(("".into(), "".into()));
+                    }
+                    index.insert(key, value.pairs.len());
+                    value.pairs.push(pairs);
+                    value.values.push(sample);
+                }
+            }
+        }
+        self.fold(&mut value.sum);
+        self.fold(&mut value.count);
+    }
+
+    /// Rewrites the labels of `_sum` or `_count` `segments`,
+    /// summing the ones which became identical.
+    fn fold(&self, segments: &mut Vec<Segment<'_>>) {
+        let mut index: HashMap<Vec<(String, String)>, usize> = HashMap::new();
+        for mut segment in std::mem::take(segments) {
+            self.rewrite(&mut segment.pairs);
+            match index.get(&key(&segment.pairs)) {
+                Some(&idx) => {
+                    let total = parse_float(&segments[idx].value) + parse_float(&segment.value);
+                    segments[idx].value = Cow::Owned(format_float(total));
+                    segments[idx].timestamp = None;
+                }
+                None => {
+                    index.insert(key(&segment.pairs), segments.len());
+                    segments.push(segment);
+                }
+            }
+        }
+    }
+}
+
+/// Returns the sorted labels of a series, identifying it
+/// within its family.
+fn key(pairs: &[LabelPair<'_>]) -> Vec<(String, String)> {
+    let mut key: Vec<(String, String)> = pairs
+        .iter()
+        .filter(|(k, _)| !k.is_empty())
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+    key.sort();
+    key
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::promerge::Context;
+
+    #[test]
+    fn test_label_rewrite() {
+        let input = r#"# HELP http_requests_total The total number of HTTP requests.
+# TYPE http_requests_total counter
+http_requests_total{method="post",code="200",path="/a"} 1027 1395066363000
+http_requests_total{method="post",code="200",path="/b"} 3 1395066363000
+http_requests_total{method="get",code="200",path="/a"} 5 1395066363000
+"#;
+        let expect = r#"# HELP http_requests_total The total number of HTTP requests.
+# TYPE http_requests_total counter
+http_requests_total{verb="post",code="200"} 1030
+http_requests_total{verb="get",code="200"} 5 1395066363000
+
+"#;
+        let mut ctx = Context::new(input);
+        ctx.set_label_rewrite(
+            LabelRewrite::new()
+                .drop("path")
+                .rename("method", "verb")
+                .unwrap(),
+        );
+        assert_eq!(ctx.run().unwrap(), expect);
+
+        // A renamed label replaces the label holding its new key.
+        let mut ctx = Context::new("# TYPE c gauge\nc{a=\"1\",b=\"2\"} 1\n");
+        ctx.set_label_rewrite(LabelRewrite::new().rename("a", "b").unwrap());
+        assert_eq!(ctx.run().unwrap(), "# TYPE c gauge\nc{b=\"1\"} 1\n\n");
+        assert!(LabelRewrite::new().rename("a", "0b").is_err());
+
+        // _sum and _count series are merged like the samples.
+        let rewrite = LabelRewrite::new().drop("path");
+        let mut ctx = Context::new(
+            "# TYPE h histogram\nh_bucket{path=\"/a\",le=\"+Inf\"} 2\n\
+             h_bucket{path=\"/b\",le=\"+Inf\"} 3\nh_sum{path=\"/a\"} 4 10\n\
+             h_sum{path=\"/b\"} 6\nh_count{path=\"/a\"} 2\nh_count{path=\"/b\"} 3\n",
+        );
+        ctx.set_label_rewrite(rewrite.clone());
+        assert_eq!(
+            ctx.run().unwrap(),
+            "# TYPE h histogram\nh_bucket{le=\"+Inf\"} 5\nh_sum 10\nh_count 5\n\n"
+        );
+        let mut ctx = Context::new(
+            "# TYPE s summary\ns{path=\"/a\",quantile=\"0.5\"} 1\n\
+             s{path=\"/b\",quantile=\"0.5\"} 2\ns_sum{path=\"/a\"} 4\n\
+             s_sum{path=\"/b\"} 6\ns_count{path=\"/a\"} 2\ns_count{path=\"/b\"} 3\n",
+        );
+        ctx.set_label_rewrite(rewrite);
+        assert_eq!(
+            ctx.run().unwrap(),
+            "# TYPE s summary\ns{quantile=\"0.5\"} 2\ns_sum 10\ns_count 5\n\n"
+        );
+    }
+}
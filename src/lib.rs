@@ -3,5 +3,29 @@ extern crate pest_derive;
 use pest::Parser;
 use pest_derive::Parser as Parse;
 
-pub mod promerge;
+pub mod aggregate;
+#[cfg(feature = "cli")]
+pub mod config;
+pub mod filter;
+pub mod intern;
+pub mod labels;
+pub mod name;
 mod parser;
+pub mod promerge;
+pub mod registry;
+pub mod relabel;
+pub mod rename;
+pub mod selector;
+#[cfg(feature = "cli")]
+pub mod server;
+pub mod staleness;
+pub mod stream;
+#[cfg(feature = "cli")]
+pub mod textfile;
+pub mod timestamp;
+
+/// Parses `input` into its metric families, without
+/// applying any of the transformations of `Context`.
+pub fn parse(input: &str) -> Result<Vec<promerge::Value<'_>>, pest::error::Error<parser::Rule>> {
+    parser::parse(input, None)
+}
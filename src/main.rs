@@ -0,0 +1,222 @@
+use std::io::{Read, Write};
+use std::process::ExitCode;
+use std::time::Duration;
+
+use promerge::config::Config;
+use promerge::promerge::Context;
+use promerge::server::{fetch, Server};
+use promerge::textfile::{write_atomic, Textfile};
+
+const USAGE: &str = "usage: promerge [--prefix <prefix>] [--label <key=value>]... <file|->...
+       promerge --config <config>
+       promerge serve <config>
+       promerge textfile [--label <name>] [--interval <secs>] [--once] <directory> <output>";
+
+/// Input is a file to merge along with its options.
+#[derive(Debug, Default, PartialEq)]
+struct Input {
+    path: String,
+    prefix: String,
+    pairs: Vec<(String, String)>,
+}
+
+/// Parses arguments where `--prefix` and `--label` apply to
+/// the next file only, `-` standing for stdin.
+fn parse_args(args: &[String]) -> Result<Vec<Input>, String> {
+    let mut inputs: Vec<Input> = Vec::new();
+    let mut next = Input::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--prefix" => {
+                next.prefix = args.next().ok_or("--prefix requires a value")?.clone();
+            }
+            "--label" => {
+                let label = args.next().ok_or("--label requires a value")?;
+                match label.split_once('=') {
+                    Some((key, value)) if !key.is_empty() => {
+                        next.pairs.push((key.into(), value.into()));
+                    }
+                    _ => return Err(format!("invalid label {:?}, expected key=value", label)),
+                }
+            }
+            "-h" | "--help" => return Err(USAGE.into()),
+            option if option.starts_with("--") => {
+                return Err(format!("unknown option {:?}", option));
+            }
+            path => {
+                next.path = path.into();
+                inputs.push(std::mem::take(&mut next));
+            }
+        }
+    }
+    if inputs.is_empty() || next != Input::default() {
+        return Err(USAGE.into());
+    }
+    Ok(inputs)
+}
+
+fn merge(args: &[String]) -> Result<(), String> {
+    let inputs = parse_args(args)?;
+    let mut contents: Vec<String> = Vec::with_capacity(inputs.len());
+    for input in &inputs {
+        let mut content = String::new();
+        let result = match input.path.as_str() {
+            "-" => std::io::stdin().read_to_string(&mut content),
+            path => std::fs::File::open(path).and_then(|mut f| f.read_to_string(&mut content)),
+        };
+        result.map_err(|err| format!("{}: {}", input.path, err))?;
+        contents.push(content);
+    }
+    let mut ctx = Context::new("");
+    for (input, content) in inputs.iter().zip(&contents) {
+        ctx.combine_with_prefix_and_pairs(content, &input.pairs, input.prefix.as_str())
+            .map_err(|err| format!("{}: {}", input.path, err))?;
+    }
+    ctx.write_io(&mut std::io::stdout().lock())
+        .map_err(|err| err.to_string())
+}
+
+/// Merges the sources of the configuration at `path`.
+fn merge_config(path: &str) -> Result<(), String> {
+    let config = Config::load(path).map_err(|err| format!("{}: {}", path, err))?;
+    let mut ctx = config
+        .context()
+        .map_err(|err| format!("{}: {}", path, err))?;
+    let timeout = Duration::from_secs(config.timeout_secs);
+    for source in &config.sources {
+        let content = match (&source.url, &source.path) {
+            (Some(url), _) => fetch(url, timeout).map_err(|err| err.to_string()),
+            (None, Some(path)) => std::fs::read_to_string(path).map_err(|err| err.to_string()),
+            (None, None) => Ok(String::new()),
+        }
+        .map_err(|err| format!("{}: {}", source.name(), err))?;
+        ctx.combine_owned(&content, &source.pairs(), source.prefix.as_str())
+            .map_err(|err| format!("{}: {}", source.name(), err))?;
+    }
+    let output = config.output.format.render(ctx.result());
+    match &config.output.path {
+        Some(path) => write_atomic(path, output.as_bytes()),
+        None => std::io::stdout().lock().write_all(output.as_bytes()),
+    }
+    .map_err(|err| err.to_string())
+}
+
+fn serve(args: &[String]) -> Result<(), String> {
+    let [path] = args else {
+        return Err(USAGE.into());
+    };
+    let config = Config::load(path).map_err(|err| format!("{}: {}", path, err))?;
+    let (context, serve_config) = config
+        .context()
+        .and_then(|context| Ok((context, config.serve_config()?)))
+        .map_err(|err| format!("{}: {}", path, err))?;
+    let server = Server::bind(serve_config, context)
+        .map_err(|err| err.to_string())?
+        .watch(path);
+    server.run().map_err(|err| err.to_string())
+}
+
+/// Merges the `*.prom` files of a directory into an output file,
+/// once or every interval.
+fn textfile(args: &[String]) -> Result<(), String> {
+    let mut label: Option<String> = None;
+    let mut interval = 5;
+    let mut once = false;
+    let mut paths: Vec<&String> = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--label" => label = Some(args.next().ok_or("--label requires a value")?.clone()),
+            "--interval" => {
+                interval = args
+                    .next()
+                    .and_then(|v| v.parse().ok())
+                    .ok_or("--interval requires a number of seconds")?;
+            }
+            "--once" => once = true,
+            option if option.starts_with("--") => {
+                return Err(format!("unknown option {:?}", option));
+            }
+            _ => paths.push(arg),
+        }
+    }
+    let [directory, output] = paths[..] else {
+        return Err(USAGE.into());
+    };
+    let mut collector = Textfile::new(directory, output, Context::new(""));
+    if let Some(label) = label {
+        collector = collector.with_label(label);
+    }
+    if !once {
+        return collector
+            .run(Duration::from_secs(interval))
+            .map_err(|err| err.to_string());
+    }
+    let rejected = collector.collect().map_err(|err| err.to_string())?;
+    for (name, err) in &rejected {
+        eprintln!("promerge: rejected {}: {}", name, err);
+    }
+    if !rejected.is_empty() {
+        return Err(format!("{} file(s) rejected", rejected.len()));
+    }
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let result = match args.first().map(String::as_str) {
+        Some("serve") => serve(&args[1..]),
+        Some("textfile") => textfile(&args[1..]),
+        Some("--config") => match &args[1..] {
+            [path] => merge_config(path),
+            _ => Err(USAGE.into()),
+        },
+        _ => merge(&args),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("promerge: {}", err);
+            ExitCode::from(2)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_args() {
+        let args: Vec<String> = [
+            "--prefix", "a_", "--label", "zone=x", "--label", "env=", "a.prom", "-",
+        ]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+        let inputs = parse_args(&args).unwrap();
+        assert_eq!(
+            inputs,
+            [
+                Input {
+                    path: "a.prom".into(),
+                    prefix: "a_".into(),
+                    pairs: vec![("zone".into(), "x".into()), ("env".into(), "".into())],
+                },
+                Input {
+                    path: "-".into(),
+                    ..Input::default()
+                },
+            ]
+        );
+        for args in [
+            &["--label", "zone", "a.prom"][..],
+            &["a.prom", "--prefix", "b_"],
+            &[],
+        ] {
+            let args: Vec<String> = args.iter().map(|s| s.to_string()).collect();
+            assert!(parse_args(&args).is_err());
+        }
+    }
+}
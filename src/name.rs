@@ -0,0 +1,113 @@
+//! Module containing validation and normalization of metric names.
+use std::borrow::Cow;
+
+use crate::promerge::{Error, Value};
+
+/// NamePolicy decides what happens to prefixes and family
+/// names that do not match the metric name grammar
+/// `[a-zA-Z_:][a-zA-Z0-9_:]*`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NamePolicy {
+    /// Fails merging the input with an error.
+    #[default]
+    Strict,
+    /// Converts illegal characters to `_` and collapses
+    /// repeated `_`, e.g. `my-service.` becomes `my_service_`.
+    Sanitize,
+}
+
+pub fn is_valid_metric_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_' || c == ':')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':')
+}
+
+/// Returns `name` with illegal characters converted to `_`,
+/// repeated `_` collapsed and a leading digit escaped.
+pub fn sanitize(name: &str) -> String {
+    let mut buffer = String::with_capacity(name.len() + 1);
+    if name.starts_with(|c: char| c.is_ascii_digit()) {
+        buffer.push('_');
+    }
+    for c in name.chars() {
+        let c = if c.is_ascii_alphanumeric() || c == ':' {
+            c
+        } else {
+            '_'
+        };
+        if c == '_' && buffer.ends_with('_') {
+            continue;
+        }
+        buffer.push(c);
+    }
+    buffer
+}
+
+impl NamePolicy {
+    /// Validates `prefix`, an empty prefix being valid.
+    pub(crate) fn prefix(&self, prefix: Option<String>) -> Result<Option<String>, Error> {
+        match prefix {
+            Some(p) if !p.is_empty() && !is_valid_metric_name(&p) => match self {
+                NamePolicy::Strict => Err(Error::InvalidName {
+                    what: "prefix",
+                    name: p,
+                }),
+                NamePolicy::Sanitize => Ok(Some(sanitize(&p))),
+            },
+            prefix => Ok(prefix),
+        }
+    }
+
+    /// Validates the prefixed family name of `value`.
+    pub(crate) fn family(&self, value: &mut Value<'_>) -> Result<(), Error> {
+        let prefix = value.prefix.as_deref().unwrap_or("");
+        let name = value.family_name();
+        if is_valid_metric_name(&format!("{}{}", prefix, name)) {
+            return Ok(());
+        }
+        if *self == NamePolicy::Strict {
+            return Err(Error::InvalidName {
+                what: "metric name",
+                name: format!("{}{}", prefix, name),
+            });
+        }
+        let old = name.to_string();
+        let name = sanitize(&old);
+        if let Some(rest) = value.key.strip_prefix(old.as_str()) {
+            value.key = format!("{}{}", name, rest);
+        }
+        if let Some(desc) = &mut value.description {
+            if !desc.name.is_empty() {
+                desc.name = Cow::Owned(name);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::promerge::Context;
+
+    #[test]
+    fn test_name_policy() {
+        let input = r#"# Minimalistic line:
+metric_without_timestamp_and_labels 12.47
+"#;
+        let expect = r#"# Minimalistic line:
+my_service_metric_without_timestamp_and_labels 12.47
+
+"#;
+        let mut ctx = Context::with_prefix(input, "my-service.");
+        assert!(matches!(
+            ctx.run(),
+            Err(Error::InvalidName { what: "prefix", .. })
+        ));
+
+        let mut ctx = Context::with_prefix(input, "my-service.");
+        ctx.set_name_policy(NamePolicy::Sanitize);
+        assert_eq!(ctx.run().unwrap(), expect);
+        assert_eq!(sanitize("9--a..b"), "_9_a_b");
+    }
+}
@@ -1,9 +1,13 @@
 //! Module containing parser for promerge.
 
+use crate::filter::Filter;
 use crate::promerge::{Desc, Kind, Segment, Value};
 
 use crate::*;
 
+#[cfg(any(test, feature = "fast-parser"))]
+mod fast;
+
 #[derive(Parse)]
 #[grammar = "./grammar.pest"]
 pub struct ExpressionParser;
@@ -16,13 +20,13 @@ where
     R: pest::RuleType,
 {
     let comment = node.next().unwrap().as_span().as_str();
-    return desc.map_or(
+    desc.map_or(
         Some(Desc::with_comment(comment)),
         move |mut v: Desc<'_>| -> Option<Desc<'_>> {
             v.comment = Some(comment.into());
             Some(v)
         },
-    );
+    )
 }
 
 fn parse_helpexpr<'i, R: pest::RuleType>(
@@ -30,14 +34,14 @@ fn parse_helpexpr<'i, R: pest::RuleType>(
     desc: Option<Desc<'i>>,
 ) -> Option<Desc<'i>> {
     let result: Vec<&str> = node.map(|v| v.as_span().as_str()).collect();
-    return desc.map_or(
+    desc.map_or(
         Some(Desc::with_help(result[0], result[1])),
         move |mut v: Desc<'_>| -> Option<Desc<'_>> {
             v.name = result[0].into();
             v.help_desc = Some(result[1].into());
             Some(v)
         },
-    );
+    )
 }
 
 fn parse_typexpr<'i, R: pest::RuleType>(
@@ -45,17 +49,49 @@ fn parse_typexpr<'i, R: pest::RuleType>(
     desc: Option<Desc<'i>>,
 ) -> Option<Desc<'i>> {
     let result: Vec<&str> = node.map(|v| v.as_span().as_str()).collect();
-    return desc.map_or(
+    desc.map_or(
         Some(Desc::new(result[0], result[1])),
         move |mut v: Desc<'_>| -> Option<Desc<'_>> {
             v.name = result[0].into();
             v.kind = Kind::from(result[1]);
             Some(v)
         },
-    );
+    )
+}
+
+/// Returns the segment opened by the `_sum` or `_count` line `key`.
+fn segment<'v, 'i>(node: Option<&'v mut Value<'i>>, key: &str) -> Option<&'v mut Segment<'i>> {
+    let node = node?;
+    if key.ends_with("_sum") {
+        node.sum.last_mut()
+    } else if key.ends_with("_count") {
+        node.count.last_mut()
+    } else {
+        None
+    }
+}
+
+/// Parses `input`, skipping blocks whose family name is
+/// rejected by `filter` before any of their lines are
+/// converted.
+///
+/// With the `fast-parser` feature the hand-written parser is
+/// tried first, falling back to the grammar for error reports.
+pub(crate) fn parse<'a>(
+    input: &'a str,
+    filter: Option<&Filter>,
+) -> Result<Vec<Value<'a>>, pest::error::Error<Rule>> {
+    #[cfg(feature = "fast-parser")]
+    if let Some(values) = fast::parse(input, filter) {
+        return Ok(values);
+    }
+    parse_grammar(input, filter)
 }
 
-pub(crate) fn parse<'a>(input: &'a str) -> Result<Vec<Value<'_>>, pest::error::Error<Rule>> {
+fn parse_grammar<'a>(
+    input: &'a str,
+    filter: Option<&Filter>,
+) -> Result<Vec<Value<'a>>, pest::error::Error<Rule>> {
     let pairs = ExpressionParser::parse(Rule::statement, input);
     if pairs.is_err() {
         return Err(pairs.err().unwrap());
@@ -63,157 +99,123 @@ pub(crate) fn parse<'a>(input: &'a str) -> Result<Vec<Value<'_>>, pest::error::E
     let mut output: Vec<Value<'_>> = Vec::new();
     let root = pairs.unwrap().next().unwrap();
     for token in root.into_inner() {
-        match token.as_rule() {
-            Rule::block => {
-                let inner = token.clone().into_inner();
-                let mut desc: Option<Desc> = None;
-                let mut node: Option<Value> = None;
-                let mut pairs: Vec<&str> = Vec::new();
-                for value in inner {
-                    match value.as_rule() {
-                        Rule::genericomment => {
-                            desc = parse_gencom(value.clone().into_inner().by_ref(), desc);
-                        }
-                        Rule::typexpr => {
-                            desc = parse_typexpr(value.clone().into_inner(), desc);
-                        }
-                        Rule::helpexpr => {
-                            desc = parse_helpexpr(value.clone().into_inner(), desc);
+        if token.as_rule() == Rule::block {
+            let inner = token.clone().into_inner();
+            let mut desc: Option<Desc> = None;
+            let mut node: Option<Value> = None;
+            let mut pairs: Vec<&str> = Vec::new();
+            let mut rejected = false;
+            for value in inner {
+                match value.as_rule() {
+                    Rule::genericomment => {
+                        desc = parse_gencom(value.clone().into_inner().by_ref(), desc);
+                    }
+                    Rule::typexpr => {
+                        desc = parse_typexpr(value.clone().into_inner(), desc);
+                    }
+                    Rule::helpexpr => {
+                        desc = parse_helpexpr(value.clone().into_inner(), desc);
+                    }
+                    Rule::promstmt => {
+                        if let (Some(filter), None) = (filter, &node) {
+                            let key = value
+                                .clone()
+                                .into_inner()
+                                .next()
+                                .map_or("", |k| k.as_span().as_str());
+                            let name = desc
+                                .as_ref()
+                                .map(|d| d.name.as_ref())
+                                .filter(|n| !n.is_empty())
+                                .unwrap_or(key);
+                            if !filter.is_allowed(name) {
+                                rejected = true;
+                                break;
+                            }
                         }
-                        Rule::promstmt => {
-                            let mut had_pairs = false;
-                            let mut nums: [&str; 2] = [""; 2];
-                            let mut nidx = 0;
-                            let mut should_skip_nums = false;
-                            let mut key: &str = "";
-                            for v in value.clone().into_inner() {
-                                match &v.as_rule() {
-                                    Rule::key => {
-                                        let name = v.as_span().as_str();
-                                        key = &name;
-                                        node = node.map_or(
-                                            Some(Value::new(name)),
-                                            |n: Value<'_>| -> Option<Value<'_>> { Some(n) },
-                                        );
+                        let mut had_pairs = false;
+                        let mut nums: [&str; 2] = [""; 2];
+                        let mut nidx = 0;
+                        let mut should_skip_nums = false;
+                        let mut key: &str = "";
+                        for v in value.clone().into_inner() {
+                            match &v.as_rule() {
+                                Rule::key => {
+                                    let name = v.as_span().as_str();
+                                    key = name;
+                                    let mut n = node.unwrap_or_else(|| Value::new(name));
+                                    // every `_sum` or `_count` line is a segment of its own.
+                                    if key.ends_with("_sum") {
+                                        n.sum.push(Segment::default());
+                                    } else if key.ends_with("_count") {
+                                        n.count.push(Segment::default());
                                     }
-                                    Rule::NaN | Rule::number | Rule::posInf | Rule::negInf => {
-                                        let is_sum = key.ends_with("_sum");
-                                        let is_count = key.ends_with("_count");
-                                        if is_sum || is_count {
-                                            should_skip_nums = true;
-                                            let mut n = node.unwrap();
-                                            let content = v.as_span().as_str();
-                                            if is_sum {
-                                                n.sum = n.sum.map_or(
-                                                    {
-                                                        let mut v = Segment::default();
-                                                        v.set_value(content);
-                                                        Some(v)
-                                                    },
-                                                    |mut v: Segment<'_>| {
-                                                        v.set_value(content);
-                                                        Some(v)
-                                                    },
-                                                );
-                                            }
-                                            if is_count {
-                                                n.count = n.count.map_or(
-                                                    {
-                                                        let mut v = Segment::default();
-                                                        v.set_value(content);
-                                                        Some(v)
-                                                    },
-                                                    |mut v: Segment<'_>| {
-                                                        v.set_value(content);
-                                                        Some(v)
-                                                    },
-                                                );
-                                            }
-                                            node = Some(n);
-                                            continue;
+                                    node = Some(n);
+                                }
+                                Rule::NaN | Rule::number | Rule::posInf | Rule::negInf => {
+                                    let content = v.as_span().as_str();
+                                    if let Some(segment) = segment(node.as_mut(), key) {
+                                        should_skip_nums = true;
+                                        if segment.value.is_empty() {
+                                            segment.set_value(content);
+                                        } else {
+                                            segment.set_timestamp(content);
                                         }
-                                        nums[nidx] = v.as_span().as_str();
-                                        nidx += 1;
+                                        continue;
                                     }
-                                    Rule::pairs => {
-                                        let is_sum = key.ends_with("_sum");
-                                        let is_count = key.ends_with("_count");
-                                        had_pairs = (is_sum || is_count) == false;
-                                        for p in v.into_inner() {
-                                            let mut inner = p.into_inner();
-                                            let key = inner.next().unwrap().as_span().as_str();
-                                            let value = inner
-                                                .next()
-                                                .unwrap()
-                                                .into_inner()
-                                                .next()
-                                                .unwrap()
-                                                .as_span()
-                                                .as_str();
-                                            if had_pairs {
-                                                pairs.push(key);
-                                                pairs.push(value);
-                                            } else {
-                                                let mut n = node.unwrap();
-                                                if is_sum {
-                                                    n.sum = n.sum.map_or(
-                                                        {
-                                                            let mut v = Segment::default();
-                                                            v.push_pairs(&[&key, &value]);
-                                                            Some(v)
-                                                        },
-                                                        |mut v: Segment<'_>| {
-                                                            v.push_pairs(&[&key, &value]);
-                                                            Some(v)
-                                                        },
-                                                    );
-                                                }
-                                                if is_count {
-                                                    n.count = n.count.map_or(
-                                                        {
-                                                            let mut v = Segment::default();
-                                                            v.push_pairs(&[&key, &value]);
-                                                            Some(v)
-                                                        },
-                                                        |mut v: Segment<'_>| {
-                                                            v.push_pairs(&[&key, &value]);
-                                                            Some(v)
-                                                        },
-                                                    );
-                                                }
-                                                node = Some(n);
-                                            }
-                                        }
+                                    nums[nidx] = content;
+                                    nidx += 1;
+                                }
+                                Rule::pairs => {
+                                    let is_segment =
+                                        key.ends_with("_sum") || key.ends_with("_count");
+                                    had_pairs = !is_segment;
+                                    for p in v.into_inner() {
+                                        let mut inner = p.into_inner();
+                                        let key = inner.next().unwrap().as_span().as_str();
+                                        let value = inner
+                                            .next()
+                                            .unwrap()
+                                            .into_inner()
+                                            .next()
+                                            .unwrap()
+                                            .as_span()
+                                            .as_str();
+                                        pairs.push(key);
+                                        pairs.push(value);
                                     }
-                                    _ => {
-                                        todo!("not implemented");
+                                    if let Some(segment) = segment(node.as_mut(), key) {
+                                        segment.push_pairs(&pairs);
+                                        pairs.clear();
                                     }
                                 }
+                                _ => {
+                                    todo!("not implemented");
+                                }
                             }
-                            let mut n = node.unwrap();
-                            n.description = desc.clone();
-                            if !should_skip_nums {
-                                n.push_values(&nums);
-                            }
-                            if !had_pairs && !should_skip_nums {
-                                pairs.push("");
-                                pairs.push("");
-                            }
-                            if pairs.len() > 0 {
-                                n.push_pairs(&pairs);
-                            }
-                            pairs.clear();
-                            nums[0] = "";
-                            nums[1] = "";
-                            node = Some(n);
                         }
-                        _ => {}
+                        let mut n = node.unwrap();
+                        n.description = desc.clone();
+                        if !should_skip_nums {
+                            n.push_values(&nums);
+                        }
+                        if !had_pairs && !should_skip_nums {
+                            pairs.push("");
+                            pairs.push("");
+                        }
+                        if !pairs.is_empty() {
+                            n.push_pairs(&pairs);
+                        }
+                        pairs.clear();
+                        node = Some(n);
                     }
+                    _ => {}
                 }
+            }
 
+            if !rejected {
                 output.push(node.unwrap());
             }
-            _ => {}
         }
     }
     Ok(output)
@@ -419,12 +421,68 @@ rpc_duration_seconds_count{another="value2"} 2693
               - EOI: ""
              */
 
-        let result = parse(&input);
+        let result = parse(input, None);
         if let Ok(ref v) = result {
             for d in v {
-                println!("{}", format!("{d}"));
+                println!("{d}");
             }
         }
-        assert_eq!(result.is_ok(), true);
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn test_fast_parser() {
+        let inputs = [
+            r#"# TYPE http_requests_total counter
+# HELP http_requests_total The total number of HTTP requests.
+http_requests_total{method="post",code="200"} 1027 1395066363000
+http_requests_total{method="post",code="400"}    3 1395066363000
+
+# Escaping in label values:
+msdos_file_access_time_seconds{path="C:\\DIR\\FILE.TXT",error="Cannot find file:\n\"FILE.TXT\""} 1.458255915e9
+
+# Minimalistic line:
+metric_without_timestamp_and_labels 12.47
+
+# A weird metric from before the epoch:
+something_weird{problem="division by zero"} +Inf -3982045
+"#,
+            r#"# A histogram, which has a pretty complex representation in the text format:
+# HELP http_request_duration_seconds A histogram of the request duration.
+# TYPE http_request_duration_seconds histogram
+http_request_duration_seconds_bucket{le="0.05"} 24054
+http_request_duration_seconds_bucket{le="+Inf"} 144320
+http_request_duration_seconds_sum 53423
+http_request_duration_seconds_count 144320
+
+# HELP rpc_duration_seconds A summary of the RPC duration in seconds.
+# TYPE rpc_duration_seconds summary
+rpc_duration_seconds{quantile="0.01"} 3102
+rpc_duration_seconds{quantile="0.99"} NaN
+rpc_duration_seconds_sum{some="value"} 1.7560473e+07
+rpc_duration_seconds_count{another="value2"} 2693"#,
+            "# TYPE up gauge\nup{} -0.5e-3",
+            "# TYPE h histogram\nh_sum 1 5\nh_count 2 6\n",
+        ];
+        let filter = Filter::new().deny_glob("http_*");
+        for input in inputs {
+            for filter in [None, Some(&filter)] {
+                let expect = format!("{:?}", parse_grammar(input, filter).unwrap());
+                let result = format!("{:?}", fast::parse(input, filter).unwrap());
+                assert_eq!(result, expect);
+            }
+        }
+        for input in [
+            "",
+            "# Only a comment\n",
+            "up 1 2 3\n",
+            "# Broken:\nup{a=\"b} 1",
+            "# TYPE up gauge\nup{a=\"b\",} 1",
+            "# TYPE up gauge\nup{a=\"b\"}\t1",
+            "# TYPE foo gauge\nfoo:bar 1",
+            "# HELP up\nup 1",
+        ] {
+            assert!(fast::parse(input, None).is_none());
+        }
     }
 }
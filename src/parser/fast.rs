@@ -0,0 +1,277 @@
+//! Module containing a hand-written parser for the hot path,
+//! producing the same model as the grammar with borrowed slices.
+//!
+//! It only accepts a subset of what the grammar does. Inputs
+//! it can not handle, or that the grammar may read
+//! differently, yield `None`, leaving them and the error
+//! report to the grammar.
+use crate::filter::Filter;
+use crate::promerge::{Desc, Kind, Segment, Value};
+
+/// State of the block being parsed.
+#[derive(Default)]
+struct Block<'a> {
+    desc: Option<Desc<'a>>,
+    node: Option<Value<'a>>,
+    rejected: bool,
+    has_samples: bool,
+}
+
+impl<'a> Block<'a> {
+    fn finish(self, output: &mut Vec<Value<'a>>) -> Option<()> {
+        if !self.has_samples {
+            return None;
+        }
+        if !self.rejected {
+            let mut node = self.node?;
+            node.description = self.desc;
+            output.push(node);
+        }
+        Some(())
+    }
+
+    // The grammar lets the spaces after `#`, `HELP`, `TYPE` and
+    // the family name span newlines, so comments which end early
+    // are left to it.
+    fn comment(&mut self, line: &'a str) -> Option<()> {
+        let rest = line[1..].trim_start_matches(' ');
+        if rest.is_empty() {
+            return None;
+        }
+        if let Some(after) = rest.strip_prefix("HELP") {
+            let after = after.trim_start_matches(' ');
+            let name = scan_name(after);
+            let help = after[name.len()..].trim_start_matches(' ');
+            if name.is_empty() || help.is_empty() {
+                return None;
+            }
+            match &mut self.desc {
+                Some(desc) => {
+                    desc.name = name.into();
+                    desc.help_desc = Some(help.into());
+                }
+                None => self.desc = Some(Desc::with_help(name, help)),
+            }
+            return Some(());
+        }
+        if let Some(after) = rest.strip_prefix("TYPE") {
+            let after = after.trim_start_matches(' ');
+            let name = scan_name(after);
+            let kind = after[name.len()..].trim_start_matches(' ');
+            let is_kind = matches!(
+                kind,
+                "counter" | "gauge" | "histogram" | "summary" | "untyped"
+            );
+            if name.is_empty() || !is_kind {
+                return None;
+            }
+            match &mut self.desc {
+                Some(desc) => {
+                    desc.name = name.into();
+                    desc.kind = Kind::from(kind);
+                }
+                None => self.desc = Some(Desc::new(name, kind)),
+            }
+            return Some(());
+        }
+        match &mut self.desc {
+            Some(desc) => desc.comment = Some(rest.into()),
+            None => self.desc = Some(Desc::with_comment(rest)),
+        }
+        Some(())
+    }
+
+    fn statement(&mut self, line: &'a str, filter: Option<&Filter>) -> Option<()> {
+        self.has_samples = true;
+        if self.rejected {
+            return Some(());
+        }
+        let key = scan_name(line);
+        if key.is_empty() {
+            return None;
+        }
+        let mut rest = &line[key.len()..];
+        let mut pairs: Vec<&'a str> = Vec::new();
+        if let Some(labels) = rest.strip_prefix('{') {
+            rest = scan_pairs(labels, &mut pairs)?;
+        } else if !rest.starts_with(' ') {
+            return None;
+        }
+        let mut nums = rest.split(' ').filter(|s| !s.is_empty());
+        let value = nums.next().filter(|n| is_number(n))?;
+        let timestamp = match nums.next() {
+            Some(n) if is_number(n) => n,
+            Some(_) => return None,
+            None => "",
+        };
+        if nums.next().is_some() {
+            return None;
+        }
+
+        if let (Some(filter), None) = (filter, &self.node) {
+            let name = self
+                .desc
+                .as_ref()
+                .map(|d| d.name.as_ref())
+                .filter(|n| !n.is_empty())
+                .unwrap_or(key);
+            if !filter.is_allowed(name) {
+                self.rejected = true;
+                return Some(());
+            }
+        }
+        let node = self.node.get_or_insert_with(|| Value::new(key));
+        let is_sum = key.ends_with("_sum");
+        let is_count = key.ends_with("_count");
+        if is_sum || is_count {
+            let segments = if is_sum {
+                &mut node.sum
+            } else {
+                &mut node.count
+            };
+            segments.push(Segment::default());
+            let segment = segments.last_mut().unwrap();
+            segment.push_pairs(&pairs);
+            segment.set_value(value);
+            if !timestamp.is_empty() {
+                segment.set_timestamp(timestamp);
+            }
+            return Some(());
+        }
+        node.push_values(&[value, timestamp]);
+        if pairs.is_empty() {
+            pairs.extend(["", ""]);
+        }
+        node.push_pairs(&pairs);
+        Some(())
+    }
+}
+
+/// Returns the number of letters at the start of `input`.
+fn scan_word(input: &str) -> usize {
+    input.bytes().take_while(u8::is_ascii_alphabetic).count()
+}
+
+/// Returns the longest metric name at the start of `input`,
+/// which the grammar limits to words of letters joined by
+/// single underscores.
+fn scan_name(input: &str) -> &str {
+    let mut end = scan_word(input);
+    while end > 0 && input[end..].starts_with('_') {
+        match scan_word(&input[end + 1..]) {
+            0 => break,
+            n => end += 1 + n,
+        }
+    }
+    &input[..end]
+}
+
+/// Returns the length of the label value at the start of
+/// `input`, up to its closing quote.
+fn scan_value(input: &str) -> Option<usize> {
+    let bytes = input.as_bytes();
+    let mut end = 0;
+    loop {
+        match *bytes.get(end)? {
+            b'"' => return Some(end),
+            b'\\' => match *bytes.get(end + 1)? {
+                b'"' | b'\\' | b'/' | b'b' | b'f' | b'n' | b'r' | b't' => end += 2,
+                b'u' if bytes
+                    .get(end + 2..end + 6)?
+                    .iter()
+                    .all(u8::is_ascii_hexdigit) =>
+                {
+                    end += 6
+                }
+                _ => return None,
+            },
+            _ => end += 1,
+        }
+    }
+}
+
+/// Scans the label pairs following `{` into `pairs` as
+/// alternating names and raw values, returning the rest of
+/// the line after `}`. Label names are letters only and no
+/// spaces are allowed, as in the grammar.
+fn scan_pairs<'a>(mut input: &'a str, pairs: &mut Vec<&'a str>) -> Option<&'a str> {
+    if let Some(rest) = input.strip_prefix('}') {
+        return Some(rest);
+    }
+    loop {
+        let name = &input[..scan_word(input)];
+        if name.is_empty() {
+            return None;
+        }
+        input = input[name.len()..].strip_prefix("=\"")?;
+        let end = scan_value(input)?;
+        pairs.push(name);
+        pairs.push(&input[..end]);
+        input = &input[end + 1..];
+        if let Some(rest) = input.strip_prefix('}') {
+            return Some(rest);
+        }
+        input = input.strip_prefix(',')?;
+    }
+}
+
+/// Returns whether `input` is a sample value or timestamp
+/// accepted by the grammar.
+fn is_number(input: &str) -> bool {
+    if matches!(input, "+Inf" | "-Inf" | "NaN") {
+        return true;
+    }
+    let bytes = input.strip_prefix('-').unwrap_or(input).as_bytes();
+    let digits = |from: usize| {
+        bytes[from..]
+            .iter()
+            .take_while(|b| b.is_ascii_digit())
+            .count()
+    };
+    let mut idx = match digits(0) {
+        0 => return false,
+        n if n > 1 && bytes[0] == b'0' => return false,
+        n => n,
+    };
+    if bytes.get(idx) == Some(&b'.') {
+        idx += 1 + digits(idx + 1);
+    }
+    if matches!(bytes.get(idx), Some(b'e' | b'E')) {
+        idx += 1;
+        if matches!(bytes.get(idx), Some(b'+' | b'-')) {
+            idx += 1;
+        }
+        match digits(idx) {
+            0 => return false,
+            n => idx += n,
+        }
+    }
+    idx == bytes.len()
+}
+
+/// Parses `input` into the same families as the grammar,
+/// or returns `None` when the input needs the full grammar.
+pub(crate) fn parse<'a>(input: &'a str, filter: Option<&Filter>) -> Option<Vec<Value<'a>>> {
+    let mut output: Vec<Value<'a>> = Vec::new();
+    let mut block: Option<Block<'a>> = None;
+    for line in input.split('\n') {
+        // blank lines may only follow samples.
+        if line.trim_matches(' ').is_empty() {
+            if !block.as_ref()?.has_samples {
+                return None;
+            }
+            continue;
+        }
+        if line.starts_with('#') {
+            if block.as_ref().is_some_and(|b| b.has_samples) {
+                block.take()?.finish(&mut output)?;
+            }
+            block.get_or_insert_with(Block::default).comment(line)?;
+        } else {
+            // every block starts with a comment.
+            block.as_mut()?.statement(line, filter)?;
+        }
+    }
+    block?.finish(&mut output)?;
+    Some(output)
+}
@@ -1,11 +1,65 @@
 //! Module containing logic to rebuild the Prometheus exposition lines.
 use std::borrow::Cow;
+use std::io::BufRead;
+use std::time::Instant;
 
-use crate::parser;
+use crate::aggregate::{self, Aggregated, Aggregation, Aggregator};
+use crate::filter::Filter;
+use crate::intern::{Interner, Label};
+use crate::labels::LabelRewrite;
+use crate::name::NamePolicy;
+use crate::parser::{self, Rule};
+use crate::relabel::{self, RelabelConfig};
+use crate::rename::Rename;
+use crate::selector::Selector;
+use crate::staleness::Staleness;
+use crate::stream::{self, StreamParser};
+use crate::timestamp::TimestampPolicy;
 
-type CowTuple<'a> = (Cow<'a, str>, Cow<'a, str>);
+type LabelPair<'a> = (Label<'a>, Label<'a>);
 
+fn owned(value: Cow<'_, str>) -> Cow<'static, str> {
+    Cow::Owned(value.into_owned())
+}
+
+fn owned_pairs(pairs: Vec<LabelPair<'_>>) -> Vec<LabelPair<'static>> {
+    pairs
+        .into_iter()
+        .map(|(k, v)| (k.into_owned(), v.into_owned()))
+        .collect()
+}
+
+/// Error returned while merging an input.
 #[derive(Debug, Clone)]
+pub enum Error {
+    /// The input is not valid exposition text.
+    Parse(Box<pest::error::Error<Rule>>),
+    /// A prefix or family name is not a valid metric name,
+    /// see `NamePolicy`.
+    InvalidName { what: &'static str, name: String },
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Parse(err) => write!(f, "{}", err),
+            Error::InvalidName { what, name } => write!(f, "invalid {} {:?}", what, name),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<pest::error::Error<Rule>> for Error {
+    fn from(err: pest::error::Error<Rule>) -> Self {
+        Error::Parse(Box::new(err))
+    }
+}
+
+/// Input of `Context::combine_batch` along with its pairs and prefix.
+pub type BatchInput<'a, 'p, S> = (&'a str, &'p [(String, String)], S);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Kind {
     Untyped,
     Counter,
@@ -25,113 +79,758 @@ pub struct Desc<'a> {
 
 /// Segment represents either '_count' or
 /// '_sum' lines.
-#[derive(Default, Debug)]
+#[derive(Default, Debug, Clone)]
 pub struct Segment<'a> {
     pub value: Cow<'a, str>,
-    pub pairs: Vec<CowTuple<'a>>,
+    pub timestamp: Option<Cow<'a, str>>,
+    pub pairs: Vec<LabelPair<'a>>,
 }
 
 /// Value represents a metric group.
 /// The construction work as follow:
 /// - push comments from description to output
 /// - for all pairs, construct a line with metric name
-///    suitable for metric type, with pairs and values
+///   suitable for metric type, with pairs and values
 /// - when applicable, print sum and count in the end
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Value<'a> {
     pub prefix: Option<String>,
     pub description: Option<Desc<'a>>,
     pub key: String,
-    pub pairs: Vec<Vec<CowTuple<'a>>>,
+    pub pairs: Vec<Vec<LabelPair<'a>>>,
     pub values: Vec<(Cow<'a, str>, Option<Cow<'a, str>>)>,
-    pub sum: Option<Segment<'a>>,
-    pub count: Option<Segment<'a>>,
+    pub sum: Vec<Segment<'a>>,
+    pub count: Vec<Segment<'a>>,
+}
+
+/// Source holds a single merged input.
+#[derive(Debug, Clone)]
+pub(crate) struct Source<'a> {
+    name: Option<String>,
+    updated: Instant,
+    body: Body<'a>,
+}
+
+/// Body holds the families of an input or, when the
+/// configuration does not need them after merging, only
+/// their rendering.
+#[derive(Debug, Clone)]
+enum Body<'a> {
+    Values(Vec<Value<'a>>),
+    Rendered(String),
 }
 
 /// Context encapsulates data for parsing
 /// and evaluating Prometheus exposition lines.
 #[derive(Debug, Clone)]
 pub struct Context<'a> {
-    input: Cow<'a, str>,
+    input: &'a str,
     prefix: Option<String>,
-    pairs: Option<&'a [(String, String)]>,
+    pairs: Option<Vec<(String, String)>>,
+    aggregation: Option<Aggregation>,
+    relabel_configs: Vec<RelabelConfig>,
+    filter: Option<Filter>,
+    rename: Option<Rename>,
+    label_rewrite: Option<LabelRewrite>,
+    selector: Option<Selector>,
+    name_policy: NamePolicy,
+    timestamp_policy: TimestampPolicy,
+    staleness: Option<Staleness>,
+    keep_values: bool,
+    interner: Option<Interner>,
+    sources: Vec<Source<'a>>,
+    aggregator: Aggregator,
+    approximate: Vec<String>,
+    non_aggregatable: Vec<String>,
     result: String,
 }
 
 impl<'a> Context<'a> {
     pub fn new(input: &'a str) -> Self {
         Self {
-            input: std::borrow::Cow::Borrowed(input),
+            input,
             prefix: None,
             pairs: None,
+            aggregation: None,
+            relabel_configs: Vec::new(),
+            filter: None,
+            rename: None,
+            label_rewrite: None,
+            selector: None,
+            name_policy: NamePolicy::default(),
+            timestamp_policy: TimestampPolicy::default(),
+            staleness: None,
+            keep_values: false,
+            interner: None,
+            sources: Vec::new(),
+            aggregator: Aggregator::default(),
+            approximate: Vec::new(),
+            non_aggregatable: Vec::new(),
             result: String::with_capacity(input.len()),
         }
     }
 
     pub fn with_prefix<S: Into<String>>(input: &'a str, prefix: S) -> Self {
         Self {
-            input: std::borrow::Cow::Borrowed(input),
             prefix: Some(prefix.into()),
-            pairs: None,
-            result: String::with_capacity(input.len()),
+            ..Self::new(input)
         }
     }
 
     pub fn with_prefix_and_pairs<S: Into<String>>(
         input: &'a str,
         prefix: S,
-        pairs: &'a [(String, String)],
+        pairs: &[(String, String)],
     ) -> Self {
         Self {
-            input: std::borrow::Cow::Borrowed(input),
             prefix: Some(prefix.into()),
-            pairs: Some(pairs),
-            result: String::with_capacity(input.len()),
+            pairs: Some(pairs.to_vec()),
+            ..Self::new(input)
         }
     }
 
+    /// Reduces matching series of all merged inputs
+    /// according to `aggregation` instead of concatenating them.
+    pub fn set_aggregation(&mut self, aggregation: Aggregation) {
+        self.aggregation = Some(aggregation);
+        self.aggregator = Aggregator::default();
+    }
+
+    /// Relabels the series of every merged input with
+    /// `configs`, in order, before they are merged.
+    pub fn set_relabel_configs(&mut self, configs: Vec<RelabelConfig>) {
+        self.relabel_configs = configs;
+    }
+
+    /// Skips families rejected by `filter` while parsing inputs.
+    pub fn set_filter(&mut self, filter: Filter) {
+        self.filter = Some(filter);
+    }
+
+    /// Renames families of every merged input with `rename`,
+    /// before relabeling.
+    pub fn set_rename(&mut self, rename: Rename) {
+        self.rename = Some(rename);
+    }
+
+    /// Removes and renames labels of every merged input with
+    /// `rewrite`, after renaming families.
+    pub fn set_label_rewrite(&mut self, rewrite: LabelRewrite) {
+        self.label_rewrite = Some(rewrite);
+    }
+
+    /// Keeps only series matching `selector` from every
+    /// merged input, after relabeling.
+    pub fn set_selector(&mut self, selector: Selector) {
+        self.selector = Some(selector);
+    }
+
+    /// Decides how prefixes and renamed families that are not
+    /// valid metric names are handled, failing by default.
+    pub fn set_name_policy(&mut self, policy: NamePolicy) {
+        self.name_policy = policy;
+    }
+
+    /// Decides the timestamps of every emitted series, keeping
+    /// those of the inputs by default.
+    pub fn set_timestamp_policy(&mut self, policy: TimestampPolicy) {
+        self.timestamp_policy = policy;
+    }
+
+    /// Omits or marks the families of inputs combined longer
+    /// than the TTL of `staleness` ago, see `refresh`.
+    pub fn set_staleness(&mut self, staleness: Staleness) {
+        self.staleness = Some(staleness);
+    }
+
+    /// Shares label names and values of every merged input
+    /// through `interner` instead of owning copies of them.
+    /// Strings of replaced or removed inputs are collected.
+    pub fn set_interner(&mut self, interner: Interner) {
+        self.interner = Some(interner);
+    }
+
+    /// Keeps the parsed families of inputs merged from now on,
+    /// so that `values` returns them whatever the configuration.
+    pub fn set_keep_values(&mut self, keep: bool) {
+        self.keep_values = keep;
+    }
+
+    /// Returns the parsed families of all merged inputs, or
+    /// `None` when some were not kept. Families are only kept
+    /// with `set_keep_values`, aggregation, staleness or
+    /// timestamps of the current time, otherwise just their
+    /// rendering is.
+    pub fn values(&self) -> Option<impl Iterator<Item = &Value<'a>>> {
+        let kept = self
+            .sources
+            .iter()
+            .all(|s| matches!(s.body, Body::Values(_)));
+        kept.then(|| self.sources.iter().flat_map(|s| s.as_ref().iter()))
+    }
+
+    /// Returns names of aggregated families whose samples
+    /// are approximate, e.g. histograms merged from differing
+    /// bucket layouts.
+    pub fn approximate_families(&self) -> &[String] {
+        &self.approximate
+    }
+
+    /// Returns names of aggregated families that had series
+    /// which can not be aggregated, such as summary quantiles.
+    pub fn non_aggregatable_families(&self) -> &[String] {
+        &self.non_aggregatable
+    }
+
     fn add_custom_attributes(
         &self,
         prefix: Option<String>,
-        pairs: Option<&'a [(String, String)]>,
-        mut result: Vec<Value<'a>>,
-    ) -> String {
-        let mut buff: String = String::new();
+        pairs: Option<&[(String, String)]>,
+        result: &mut [Value<'a>],
+    ) {
         let prefix: String = prefix.unwrap_or("".into());
         let pairs: &[(String, String)] = pairs.unwrap_or(&[]);
-        for v in &mut result {
+        let pairs: Vec<LabelPair<'a>> = match &self.interner {
+            Some(interner) => pairs
+                .iter()
+                .map(|p| (interner.intern(&p.0).into(), interner.intern(&p.1).into()))
+                .collect(),
+            None => pairs
+                .iter()
+                .map(|p| (p.0.clone().into(), p.1.clone().into()))
+                .collect(),
+        };
+        for v in result {
             v.prefix = Some(prefix.clone());
             for vp in &mut v.pairs {
-                for p in pairs {
-                    vp.push((
-                        std::borrow::Cow::Borrowed(&p.0),
-                        std::borrow::Cow::Borrowed(&p.1),
-                    ));
+                // custom labels replace the labels of the input.
+                for (key, value) in &pairs {
+                    match vp.iter_mut().find(|(k, _)| k == key) {
+                        Some(pair) => pair.1 = value.clone(),
+                        None => vp.push((key.clone(), value.clone())),
+                    }
                 }
             }
-            buff.push_str(v.to_string().as_str());
         }
-        buff
     }
 
-    pub fn run(&mut self) -> Result<String, pest::error::Error<crate::parser::Rule>> {
-        let result = parser::parse(self.input.as_ref())?;
-        let buff = self.add_custom_attributes(self.prefix.clone(), self.pairs.clone(), result);
-        self.result.extend(buff.chars());
+    /// Runs the configured renaming, relabeling and selection
+    /// over the families of an input. Timestamps are decided
+    /// when rendering, see `write_value`.
+    fn transform(&self, mut result: Vec<Value<'a>>) -> Result<Vec<Value<'a>>, Error> {
+        if let Some(rename) = &self.rename {
+            result.iter_mut().for_each(|v| rename.rename(v));
+        }
+        if let Some(rewrite) = &self.label_rewrite {
+            result.iter_mut().for_each(|v| rewrite.apply(v));
+        }
+        let mut result = relabel::relabel(result, &self.relabel_configs);
+        for v in &mut result {
+            self.name_policy.family(v)?;
+        }
+        if let Some(selector) = &self.selector {
+            result = result
+                .into_iter()
+                .filter_map(|v| selector.retain(v))
+                .collect();
+        }
+        Ok(result)
+    }
+
+    fn evaluate(&mut self, name: Option<String>, result: Vec<Value<'a>>) -> Result<(), Error> {
+        let source = self.source(name, self.transform(result)?);
+        self.store(source);
+        Ok(())
+    }
 
-        Ok(self.result.clone())
+    /// Parses and transforms `input` into an owned source,
+    /// without merging it.
+    pub(crate) fn prepare_owned(
+        &self,
+        name: Option<String>,
+        input: &str,
+        pairs: &[(String, String)],
+        prefix: String,
+    ) -> Result<Source<'a>, Error> {
+        let prefix = self.name_policy.prefix(Some(prefix))?;
+        let mut result: Vec<Value<'a>> = parser::parse(input, self.filter.as_ref())?
+            .into_iter()
+            .map(Value::into_owned)
+            .collect();
+        self.add_custom_attributes(prefix, Some(pairs), &mut result);
+        Ok(self.source(name, self.transform(result)?))
+    }
+
+    /// Returns `values` as a source, keeping the families only
+    /// when asked to or rendering the output needs them again.
+    /// Their labels are then shared through the interner.
+    fn source(&self, name: Option<String>, mut values: Vec<Value<'a>>) -> Source<'a> {
+        let body = if self.keep_values
+            || self.aggregation.is_some()
+            || self.staleness.is_some()
+            || self.timestamp_policy == TimestampPolicy::Now
+        {
+            if let Some(interner) = &self.interner {
+                values.iter_mut().for_each(|v| interner.intern_value(v));
+            }
+            Body::Values(values)
+        } else {
+            let policy = self.timestamp_policy.resolve();
+            let mut rendered = String::new();
+            for v in &values {
+                write_value(v, policy, &mut rendered).unwrap();
+            }
+            Body::Rendered(rendered)
+        };
+        Source {
+            name,
+            updated: Instant::now(),
+            body,
+        }
+    }
+
+    /// Drops interned labels no longer used by any source.
+    pub(crate) fn collect_labels(&self) {
+        if let Some(interner) = &self.interner {
+            interner.collect();
+        }
+    }
+
+    /// Merges `source`, replacing the source of the same name.
+    fn store(&mut self, source: Source<'a>) {
+        let position = source.name.as_ref().and_then(|name| {
+            self.sources
+                .iter()
+                .position(|s| s.name.as_ref() == Some(name))
+        });
+        match position {
+            Some(idx) => {
+                self.sources[idx] = source;
+                self.collect_labels();
+                self.render(None);
+            }
+            None => {
+                self.sources.push(source);
+                self.render(Some(1));
+            }
+        }
+    }
+
+    /// Renders the merged inputs into `result` once the last
+    /// `appended` of them were added, or all of them on `None`.
+    /// Appended inputs are folded into the series aggregated so
+    /// far, or appended to the output without aggregation,
+    /// unless staleness or timestamps of the current time need
+    /// every input to be rendered again.
+    fn render(&mut self, appended: Option<usize>) {
+        let start = appended.map(|n| self.sources.len() - n);
+        if self.staleness.is_none() {
+            if let Some(aggregation) = &self.aggregation {
+                if start.is_none() {
+                    self.aggregator = Aggregator::default();
+                }
+                for source in &self.sources[self.aggregator.inputs()..] {
+                    self.aggregator.add(source.as_ref(), aggregation);
+                }
+                let sources: Vec<&Source<'a>> = self.sources.iter().collect();
+                let aggregated = self.aggregator.finish(&sources, aggregation);
+                let mut result = std::mem::take(&mut self.result);
+                result.clear();
+                (self.approximate, self.non_aggregatable) =
+                    self.write_aggregated(aggregated, &sources, &mut result);
+                self.result = result;
+                return;
+            }
+            if let (Some(start), false) = (start, self.timestamp_policy == TimestampPolicy::Now) {
+                let policy = self.timestamp_policy.resolve();
+                for source in &self.sources[start..] {
+                    source.write(policy, &mut self.result);
+                }
+                return;
+            }
+        }
+        let mut result = std::mem::take(&mut self.result);
+        result.clear();
+        let sources: Vec<&Source<'a>> = self.sources.iter().collect();
+        if let Some((approximate, non_aggregatable)) = self.compose(&sources, &mut result) {
+            self.approximate = approximate;
+            self.non_aggregatable = non_aggregatable;
+        }
+        self.result = result;
+    }
+
+    /// Writes `sources` merged according to the configuration
+    /// into `sink`. With aggregation, returns the names of
+    /// approximate and non-aggregatable families.
+    pub(crate) fn compose(
+        &self,
+        sources: &[&Source<'a>],
+        sink: &mut String,
+    ) -> Option<(Vec<String>, Vec<String>)> {
+        let now = Instant::now();
+        let policy = self.timestamp_policy.resolve();
+        if let Some(aggregation) = &self.aggregation {
+            let aggregated = match &self.staleness {
+                Some(staleness) => {
+                    let sources: Vec<Source<'a>> = sources
+                        .iter()
+                        .map(|s| Source {
+                            name: s.name.clone(),
+                            updated: s.updated,
+                            body: match &s.body {
+                                Body::Values(values) => Body::Values(
+                                    staleness.apply(values, now.duration_since(s.updated)),
+                                ),
+                                Body::Rendered(rendered) => Body::Rendered(rendered.clone()),
+                            },
+                        })
+                        .collect();
+                    let sources: Vec<&Source<'a>> = sources.iter().collect();
+                    aggregate::aggregate(&sources, aggregation)
+                }
+                None => aggregate::aggregate(sources, aggregation),
+            };
+            return Some(self.write_aggregated(aggregated, sources, sink));
+        }
+        for source in sources {
+            let age = now.duration_since(source.updated);
+            match (&self.staleness, &source.body) {
+                (Some(staleness), Body::Values(values)) if staleness.is_stale(age) => {
+                    for v in staleness.apply(values, age) {
+                        write_value(&v, policy, sink).unwrap();
+                    }
+                }
+                _ => source.write(policy, sink),
+            }
+        }
+        None
+    }
+
+    /// Writes `aggregated` into `sink`, followed by the sources
+    /// merged before aggregation was configured, which are only
+    /// kept rendered. Returns the names of approximate and
+    /// non-aggregatable families.
+    fn write_aggregated(
+        &self,
+        aggregated: Aggregated<'a>,
+        sources: &[&Source<'a>],
+        sink: &mut String,
+    ) -> (Vec<String>, Vec<String>) {
+        let policy = self.timestamp_policy.resolve();
+        for v in &aggregated.values {
+            write_value(v, policy, sink).unwrap();
+        }
+        for source in sources {
+            if let Body::Rendered(rendered) = &source.body {
+                sink.push_str(rendered);
+            }
+        }
+        (aggregated.approximate, aggregated.non_aggregatable)
+    }
+
+    /// Renders all merged inputs again without combining a
+    /// new one, e.g. to apply staleness at the current time.
+    pub fn refresh(&mut self) -> &str {
+        if !self.sources.is_empty() {
+            self.render(None);
+        }
+        &self.result
+    }
+
+    /// Returns the output accumulated so far.
+    pub fn result(&self) -> &str {
+        &self.result
+    }
+
+    /// Writes the output accumulated so far into `sink`.
+    pub fn write_to<W: std::fmt::Write>(&self, sink: &mut W) -> std::fmt::Result {
+        sink.write_str(&self.result)
+    }
+
+    /// Writes the output accumulated so far into `sink`.
+    pub fn write_io<W: std::io::Write>(&self, sink: &mut W) -> std::io::Result<()> {
+        sink.write_all(self.result.as_bytes())
+    }
+
+    /// Replaces the contribution of the source `name`, using
+    /// the prefix and pairs of the context, or adds it when no
+    /// source of that name was merged yet. Other sources are
+    /// not parsed again.
+    pub fn update_source<S: Into<String>>(
+        &mut self,
+        name: S,
+        input: &'a str,
+    ) -> Result<&str, Error> {
+        let prefix = self.name_policy.prefix(self.prefix.clone())?;
+        let mut result = parser::parse(input, self.filter.as_ref())?;
+        self.add_custom_attributes(prefix, self.pairs.as_deref(), &mut result);
+        self.evaluate(Some(name.into()), result)?;
+
+        Ok(&self.result)
+    }
+
+    /// Same as `update_source` with the given `prefix` and `pairs`.
+    pub fn update_source_with_prefix_and_pairs<N, S>(
+        &mut self,
+        name: N,
+        input: &'a str,
+        pairs: &[(String, String)],
+        prefix: S,
+    ) -> Result<&str, Error>
+    where
+        N: Into<String>,
+        S: Into<String>,
+    {
+        let prefix = self.name_policy.prefix(Some(prefix.into()))?;
+        let mut result = parser::parse(input, self.filter.as_ref())?;
+        self.add_custom_attributes(prefix, Some(pairs), &mut result);
+        self.evaluate(Some(name.into()), result)?;
+
+        Ok(&self.result)
+    }
+
+    /// Merges `inputs`, each given with its pairs and prefix,
+    /// with the same result as combining them one after another.
+    /// With the `rayon` feature, inputs are parsed and rendered
+    /// in parallel. Nothing is merged if any input fails.
+    pub fn combine_batch<S>(&mut self, inputs: &[BatchInput<'a, '_, S>]) -> Result<&str, Error>
+    where
+        S: AsRef<str> + Sync,
+    {
+        let prepare = |(input, pairs, prefix): &BatchInput<'a, '_, S>| {
+            let prefix = self.name_policy.prefix(Some(prefix.as_ref().into()))?;
+            let mut result = parser::parse(input, self.filter.as_ref())?;
+            self.add_custom_attributes(prefix, Some(pairs), &mut result);
+            Ok::<_, Error>(self.source(None, self.transform(result)?))
+        };
+        #[cfg(feature = "rayon")]
+        let sources: Result<Vec<Source<'a>>, _> = {
+            use rayon::prelude::*;
+            inputs.par_iter().map(prepare).collect()
+        };
+        #[cfg(not(feature = "rayon"))]
+        let sources: Result<Vec<Source<'a>>, _> = inputs.iter().map(prepare).collect();
+        let sources = sources?;
+        let appended = sources.len();
+        self.sources.extend(sources);
+        self.render(Some(appended));
+
+        Ok(&self.result)
+    }
+
+    /// Same as `combine_with_prefix_and_pairs`, reading the
+    /// input from `reader` without holding it in memory at once.
+    pub fn combine_reader<R: BufRead, S: Into<String>>(
+        &mut self,
+        reader: R,
+        pairs: &[(String, String)],
+        prefix: S,
+    ) -> Result<&str, stream::Error> {
+        let prefix = self.name_policy.prefix(Some(prefix.into()))?;
+        let mut result: Vec<Value<'a>> = Vec::new();
+        for value in StreamParser::new(reader).with_filter(self.filter.clone()) {
+            result.push(value?);
+        }
+        self.add_custom_attributes(prefix, Some(pairs), &mut result);
+        self.evaluate(None, result)?;
+
+        Ok(&self.result)
+    }
+
+    /// Merges the input read from `reader` into `sink` family
+    /// by family, so memory stays bounded by the largest family.
+    /// The input is not kept, hence neither aggregation nor
+    /// staleness apply and the accumulated output is unchanged.
+    pub fn stream<R, W, S>(
+        &self,
+        reader: R,
+        pairs: &[(String, String)],
+        prefix: S,
+        sink: &mut W,
+    ) -> Result<(), stream::Error>
+    where
+        R: BufRead,
+        W: std::io::Write,
+        S: Into<String>,
+    {
+        let prefix = self.name_policy.prefix(Some(prefix.into()))?;
+        let policy = self.timestamp_policy.resolve();
+        let mut buffer = String::new();
+        for value in StreamParser::new(reader).with_filter(self.filter.clone()) {
+            let mut result = vec![value?];
+            self.add_custom_attributes(prefix.clone(), Some(pairs), &mut result);
+            for v in self.transform(result)? {
+                buffer.clear();
+                write_value(&v, policy, &mut buffer).unwrap();
+                sink.write_all(buffer.as_bytes())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Same as `combine_with_prefix_and_pairs`, copying the
+    /// parsed families so that `input` is not borrowed beyond
+    /// the call, e.g. for a `Context<'static>` fed from network
+    /// buffers.
+    pub fn combine_owned<S: Into<String>>(
+        &mut self,
+        input: &str,
+        pairs: &[(String, String)],
+        prefix: S,
+    ) -> Result<&str, Error> {
+        let source = self.prepare_owned(None, input, pairs, prefix.into())?;
+        self.store(source);
+
+        Ok(&self.result)
+    }
+
+    /// Same as `update_source_with_prefix_and_pairs`, copying
+    /// the parsed families like `combine_owned`.
+    pub fn update_source_owned<N, S>(
+        &mut self,
+        name: N,
+        input: &str,
+        pairs: &[(String, String)],
+        prefix: S,
+    ) -> Result<&str, Error>
+    where
+        N: Into<String>,
+        S: Into<String>,
+    {
+        let source = self.prepare_owned(Some(name.into()), input, pairs, prefix.into())?;
+        self.store(source);
+
+        Ok(&self.result)
+    }
+
+    /// Returns a context that no longer borrows from any input,
+    /// which can be stored and sent across threads. Inputs
+    /// merged so far are copied, except for interned labels,
+    /// while the input given at construction is not kept.
+    pub fn into_owned(self) -> Context<'static> {
+        Context {
+            input: "",
+            prefix: self.prefix,
+            pairs: self.pairs,
+            aggregation: self.aggregation,
+            relabel_configs: self.relabel_configs,
+            filter: self.filter,
+            rename: self.rename,
+            label_rewrite: self.label_rewrite,
+            selector: self.selector,
+            name_policy: self.name_policy,
+            timestamp_policy: self.timestamp_policy,
+            staleness: self.staleness,
+            keep_values: self.keep_values,
+            interner: self.interner,
+            sources: self.sources.into_iter().map(Source::into_owned).collect(),
+            aggregator: self.aggregator,
+            approximate: self.approximate,
+            non_aggregatable: self.non_aggregatable,
+            result: self.result,
+        }
+    }
+
+    /// Same as `combine_reader`, reading the input from a
+    /// tokio `reader` without blocking the executor.
+    #[cfg(feature = "tokio")]
+    pub async fn combine_async<R, S>(
+        &mut self,
+        reader: R,
+        pairs: &[(String, String)],
+        prefix: S,
+    ) -> Result<&str, stream::Error>
+    where
+        R: tokio::io::AsyncBufRead + Unpin,
+        S: Into<String>,
+    {
+        let prefix = self.name_policy.prefix(Some(prefix.into()))?;
+        let mut parser = stream::AsyncStreamParser::new(reader).with_filter(self.filter.clone());
+        let mut result: Vec<Value<'a>> = Vec::new();
+        while let Some(value) = parser.next_family().await {
+            result.push(value?);
+        }
+        self.add_custom_attributes(prefix, Some(pairs), &mut result);
+        self.evaluate(None, result)?;
+
+        Ok(&self.result)
+    }
+
+    /// Same as `stream`, reading from and writing to tokio
+    /// `reader` and `sink`.
+    #[cfg(feature = "tokio")]
+    pub async fn stream_async<R, W, S>(
+        &self,
+        reader: R,
+        pairs: &[(String, String)],
+        prefix: S,
+        sink: &mut W,
+    ) -> Result<(), stream::Error>
+    where
+        R: tokio::io::AsyncBufRead + Unpin,
+        W: tokio::io::AsyncWrite + Unpin,
+        S: Into<String>,
+    {
+        use tokio::io::AsyncWriteExt;
+        let prefix = self.name_policy.prefix(Some(prefix.into()))?;
+        let mut parser = stream::AsyncStreamParser::new(reader).with_filter(self.filter.clone());
+        let policy = self.timestamp_policy.resolve();
+        let mut buffer = String::new();
+        while let Some(value) = parser.next_family().await {
+            let mut result: Vec<Value<'a>> = vec![value?];
+            self.add_custom_attributes(prefix.clone(), Some(pairs), &mut result);
+            for v in self.transform(result)? {
+                buffer.clear();
+                write_value(&v, policy, &mut buffer).unwrap();
+                sink.write_all(buffer.as_bytes()).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the output accumulated so far into tokio `sink`.
+    #[cfg(feature = "tokio")]
+    pub async fn write_async<W>(&self, sink: &mut W) -> std::io::Result<()>
+    where
+        W: tokio::io::AsyncWrite + Unpin,
+    {
+        use tokio::io::AsyncWriteExt;
+        sink.write_all(self.result.as_bytes()).await
+    }
+
+    /// Removes the contribution of the source `name`, returning
+    /// whether it existed.
+    pub fn remove_source(&mut self, name: &str) -> bool {
+        let len = self.sources.len();
+        self.sources.retain(|s| s.name.as_deref() != Some(name));
+        if self.sources.len() == len {
+            return false;
+        }
+        self.collect_labels();
+        self.render(None);
+        true
+    }
+
+    pub fn run(&mut self) -> Result<&str, Error> {
+        let prefix = self.name_policy.prefix(self.prefix.clone())?;
+        let mut result = parser::parse(self.input, self.filter.as_ref())?;
+        self.add_custom_attributes(prefix, self.pairs.as_deref(), &mut result);
+        self.evaluate(None, result)?;
+
+        Ok(&self.result)
     }
 
     pub fn combine_with_prefix<S: Into<String>>(
         &mut self,
         input: &'a str,
         prefix: S,
-    ) -> Result<String, pest::error::Error<crate::parser::Rule>> {
-        let result = parser::parse(input)?;
-        let buff = self.add_custom_attributes(Some(prefix.into()), None, result);
-        self.result.extend(buff.chars());
+    ) -> Result<&str, Error> {
+        let prefix = self.name_policy.prefix(Some(prefix.into()))?;
+        let mut result = parser::parse(input, self.filter.as_ref())?;
+        self.add_custom_attributes(prefix, None, &mut result);
+        self.evaluate(None, result)?;
 
-        Ok(self.result.clone())
+        Ok(&self.result)
     }
 
     pub fn combine_with_prefix_and_pairs<S: Into<String>>(
@@ -139,19 +838,86 @@ impl<'a> Context<'a> {
         input: &'a str,
         pairs: &[(String, String)],
         prefix: S,
-    ) -> Result<String, pest::error::Error<crate::parser::Rule>> {
-        let result = parser::parse(input)?;
-        let buff = self.add_custom_attributes(Some(prefix.into()), Some(pairs), result);
-        self.result.extend(buff.chars());
+    ) -> Result<&str, Error> {
+        let prefix = self.name_policy.prefix(Some(prefix.into()))?;
+        let mut result = parser::parse(input, self.filter.as_ref())?;
+        self.add_custom_attributes(prefix, Some(pairs), &mut result);
+        self.evaluate(None, result)?;
+
+        Ok(&self.result)
+    }
+}
+
+impl<'a> Source<'a> {
+    pub(crate) fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Returns a source without any family, e.g. for a slot
+    /// awaiting its first update.
+    pub(crate) fn empty(name: String) -> Self {
+        Self {
+            name: Some(name),
+            updated: Instant::now(),
+            body: Body::Values(Vec::new()),
+        }
+    }
+
+    /// Writes the families of the source into `sink`, with the
+    /// timestamps decided by the resolved `policy` unless they
+    /// were already rendered.
+    fn write(&self, policy: TimestampPolicy, sink: &mut String) {
+        match &self.body {
+            Body::Values(values) => {
+                for v in values {
+                    write_value(v, policy, sink).unwrap();
+                }
+            }
+            Body::Rendered(rendered) => sink.push_str(rendered),
+        }
+    }
+
+    fn into_owned(self) -> Source<'static> {
+        Source {
+            name: self.name,
+            updated: self.updated,
+            body: match self.body {
+                Body::Values(values) => {
+                    Body::Values(values.into_iter().map(Value::into_owned).collect())
+                }
+                Body::Rendered(rendered) => Body::Rendered(rendered),
+            },
+        }
+    }
+}
 
-        Ok(self.result.clone())
+/// Writes `value` into `sink` with the timestamps decided
+/// by `policy`, which has to be resolved.
+fn write_value<W: std::fmt::Write>(
+    value: &Value<'_>,
+    policy: TimestampPolicy,
+    sink: &mut W,
+) -> std::fmt::Result {
+    if policy == TimestampPolicy::Keep {
+        return value.render(sink);
+    }
+    let mut value = value.clone();
+    policy.apply(&mut value);
+    value.render(sink)
+}
+
+impl<'a> AsRef<[Value<'a>]> for Source<'a> {
+    fn as_ref(&self) -> &[Value<'a>] {
+        match &self.body {
+            Body::Values(values) => values,
+            Body::Rendered(_) => &[],
+        }
     }
 }
 
 impl<'a> Value<'a> {
-    fn to_string(&self) -> String {
+    fn render_lines<W: std::fmt::Write>(&self, sink: &mut W) -> std::fmt::Result {
         use std::fmt::Write;
-        let mut buffer: String = String::new();
         let lenpairs = self.pairs.len();
         let lenvalues = self.values.len();
         let prefix = if let Some(p) = &self.prefix {
@@ -159,32 +925,16 @@ impl<'a> Value<'a> {
         } else {
             ""
         };
-        let is_histogram = if let Some(k) = &self.description {
-            match k.kind {
-                Kind::Histogram => true,
-                _ => false,
-            }
-        } else {
-            false
-        };
-        let key = if let Some(k) = &self.description {
-            let nk = k.name.as_ref();
-            if nk.is_empty() {
-                self.key.as_ref()
-            } else {
-                nk
-            }
-        } else {
-            self.key.as_ref()
-        };
+        let is_histogram = matches!(self.kind(), Kind::Histogram);
+        let key = self.family_name();
 
         if lenpairs == 0 && lenvalues > 0 {
             for v in &self.values {
                 let lval = v.0.as_ref();
                 if let Some(rval) = &v.1 {
-                    writeln!(buffer, "{}{} {} {}", &prefix, &key, lval, rval.as_ref()).unwrap();
+                    writeln!(sink, "{}{} {} {}", &prefix, &key, lval, rval.as_ref())?;
                 } else {
-                    writeln!(buffer, "{}{} {}", &prefix, &key, lval).unwrap();
+                    writeln!(sink, "{}{} {}", &prefix, &key, lval)?;
                 }
             }
         } else {
@@ -208,99 +958,88 @@ impl<'a> Value<'a> {
                 let lval = v.0.as_ref();
                 if let Some(rval) = &v.1 {
                     if !had_tuple {
-                        writeln!(buffer, "{}{} {} {}", &prefix, &key, lval, rval.as_ref()).unwrap();
+                        writeln!(sink, "{}{} {} {}", &prefix, &key, lval, rval.as_ref())?;
                     } else {
                         if is_histogram {
                             writeln!(
-                                buffer,
+                                sink,
                                 "{}{}_bucket{{{}}} {} {}",
                                 &prefix,
                                 &key,
                                 pbuff,
                                 lval,
                                 rval.as_ref()
-                            )
-                            .unwrap();
+                            )?;
                         } else {
                             writeln!(
-                                buffer,
+                                sink,
                                 "{}{}{{{}}} {} {}",
                                 &prefix,
                                 &key,
                                 pbuff,
                                 lval,
                                 rval.as_ref()
-                            )
-                            .unwrap();
+                            )?;
                         }
                     }
                 } else {
                     if !had_tuple {
-                        writeln!(buffer, "{}{} {}", &prefix, &key, lval).unwrap();
+                        writeln!(sink, "{}{} {}", &prefix, &key, lval)?;
                     } else {
                         if is_histogram {
-                            writeln!(buffer, "{}{}_bucket{{{}}} {}", &prefix, &key, pbuff, lval)
-                                .unwrap();
+                            writeln!(sink, "{}{}_bucket{{{}}} {}", &prefix, &key, pbuff, lval)?;
                         } else {
-                            writeln!(buffer, "{}{}{{{}}} {}", &prefix, &key, pbuff, lval).unwrap();
+                            writeln!(sink, "{}{}{{{}}} {}", &prefix, &key, pbuff, lval)?;
                         }
                     }
                 }
             }
         }
-        if let Some(sum) = &self.sum {
-            writeln!(buffer, "{}{}_sum{}", &prefix, &key, sum.to_string()).unwrap();
+        for sum in &self.sum {
+            writeln!(sink, "{}{}_sum{}", &prefix, &key, sum)?;
         }
-        if let Some(count) = &self.count {
-            writeln!(buffer, "{}{}_count{}", &prefix, &key, count.to_string()).unwrap();
+        for count in &self.count {
+            writeln!(sink, "{}{}_count{}", &prefix, &key, count)?;
         }
-        buffer
+        Ok(())
     }
 }
 
 impl<'a> Desc<'a> {
-    fn to_string(&self, prefix: &Option<String>) -> String {
-        let mut buffer: String = String::new();
-        let kind = self.kind.to_string();
-        let prefix = if let Some(p) = &prefix { &p } else { "" };
-        use std::fmt::Write;
+    fn render<W: std::fmt::Write>(
+        &self,
+        prefix: &Option<String>,
+        sink: &mut W,
+    ) -> std::fmt::Result {
+        let kind = self.kind.as_str();
+        let prefix = if let Some(p) = &prefix { p } else { "" };
         if let Some(comment) = &self.comment {
-            writeln!(buffer, "# {}", comment.as_ref()).unwrap();
+            writeln!(sink, "# {}", comment.as_ref())?;
         }
 
         if let Some(help_desc) = &self.help_desc {
             writeln!(
-                buffer,
-                "# HELP {} {}",
-                format!("{}{}", &prefix, self.name.as_ref()),
+                sink,
+                "# HELP {}{} {}",
+                &prefix,
+                self.name.as_ref(),
                 help_desc.as_ref()
-            )
-            .unwrap();
+            )?;
         }
         match &self.kind {
             Kind::Untyped => {}
             _ => {
-                writeln!(
-                    buffer,
-                    "# TYPE {} {}",
-                    format!("{}{}", &prefix, self.name.as_ref()),
-                    kind
-                )
-                .unwrap();
+                writeln!(sink, "# TYPE {}{} {}", &prefix, self.name.as_ref(), kind)?;
             }
         };
 
-        buffer
+        Ok(())
     }
 }
 
 impl<'a> std::fmt::Display for Value<'a> {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> Result<(), core::fmt::Error> {
-        if let Some(desc) = &self.description {
-            write!(f, "{}", desc.to_string(&self.prefix)).unwrap();
-        }
-        writeln!(f, "{}", self.to_string()).unwrap();
-        Ok(())
+        self.render(f)
     }
 }
 
@@ -311,11 +1050,11 @@ impl Kind {
             "gauge" => Kind::Gauge,
             "histogram" => Kind::Histogram,
             "summary" => Kind::Summary,
-            "untyped" | _ => Kind::Untyped,
+            _ => Kind::Untyped,
         }
     }
 
-    fn to_string(&self) -> &str {
+    fn as_str(&self) -> &str {
         match &self {
             Kind::Counter => "counter",
             Kind::Gauge => "gauge",
@@ -345,6 +1084,16 @@ impl<'a> Desc<'a> {
         }
     }
 
+    /// Returns the description with all borrowed strings copied.
+    pub fn into_owned(self) -> Desc<'static> {
+        Desc {
+            kind: self.kind,
+            name: owned(self.name),
+            help_desc: self.help_desc.map(owned),
+            comment: self.comment.map(owned),
+        }
+    }
+
     pub fn with_comment(comment: &'a str) -> Self {
         Self {
             kind: Kind::Untyped,
@@ -363,8 +1112,50 @@ impl<'a> Value<'a> {
             key: key.into(),
             pairs: Vec::new(),
             values: Vec::new(),
-            sum: None,
-            count: None,
+            sum: Vec::new(),
+            count: Vec::new(),
+        }
+    }
+
+    /// Returns the metric type declared by the `# TYPE` line.
+    pub fn kind(&self) -> Kind {
+        self.description.as_ref().map_or(Kind::Untyped, |d| d.kind)
+    }
+
+    /// Returns the family name without prefix, as declared by
+    /// the `# HELP` or `# TYPE` line, or the first sample key.
+    pub fn family_name(&self) -> &str {
+        match &self.description {
+            Some(d) if !d.name.is_empty() => d.name.as_ref(),
+            _ => self.key.as_ref(),
+        }
+    }
+
+    /// Writes the exposition lines of the family into `sink`,
+    /// followed by an empty line.
+    pub fn render<W: std::fmt::Write>(&self, sink: &mut W) -> std::fmt::Result {
+        if let Some(desc) = &self.description {
+            desc.render(&self.prefix, sink)?;
+        }
+        self.render_lines(sink)?;
+        writeln!(sink)
+    }
+
+    /// Returns the family with all borrowed strings copied,
+    /// so that it no longer borrows from the input.
+    pub fn into_owned(self) -> Value<'static> {
+        Value {
+            prefix: self.prefix,
+            description: self.description.map(Desc::into_owned),
+            key: self.key,
+            pairs: self.pairs.into_iter().map(owned_pairs).collect(),
+            values: self
+                .values
+                .into_iter()
+                .map(|(v, t)| (owned(v), t.map(owned)))
+                .collect(),
+            sum: self.sum.into_iter().map(Segment::into_owned).collect(),
+            count: self.count.into_iter().map(Segment::into_owned).collect(),
         }
     }
 
@@ -390,7 +1181,7 @@ impl<'a> Value<'a> {
     }
 
     pub(crate) fn push_pairs<'b>(&mut self, values: &'b [&'a str]) {
-        let mut result: Vec<CowTuple<'a>> = Vec::with_capacity(values.len());
+        let mut result: Vec<LabelPair<'a>> = Vec::with_capacity(values.len());
         for slice in values.chunks_exact(2) {
             result.push((slice[0].into(), slice[1].into()));
         }
@@ -399,9 +1190,23 @@ impl<'a> Value<'a> {
 }
 
 impl<'a> Segment<'a> {
+    /// Returns the segment with all borrowed strings copied.
+    pub fn into_owned(self) -> Segment<'static> {
+        Segment {
+            value: owned(self.value),
+            timestamp: self.timestamp.map(owned),
+            pairs: owned_pairs(self.pairs),
+        }
+    }
+
     #[inline]
     pub fn set_value(&mut self, value: &'a str) {
-        self.value = std::borrow::Cow::Borrowed(&value);
+        self.value = std::borrow::Cow::Borrowed(value);
+    }
+
+    #[inline]
+    pub fn set_timestamp(&mut self, timestamp: &'a str) {
+        self.timestamp = Some(std::borrow::Cow::Borrowed(timestamp));
     }
 
     #[inline]
@@ -410,31 +1215,33 @@ impl<'a> Segment<'a> {
             self.pairs.push((slice[0].into(), slice[1].into()));
         }
     }
+}
 
-    pub fn to_string(&self) -> String {
-        let mut buffer: String = String::new();
-        use std::fmt::Write;
-
+impl<'a> std::fmt::Display for Segment<'a> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> Result<(), core::fmt::Error> {
         let lenpairs = self.pairs.len();
         let should_append_bracket = lenpairs > 0;
         if should_append_bracket {
-            write!(buffer, "{{").unwrap();
+            write!(f, "{{")?;
         }
         for (i, p) in self.pairs.iter().enumerate() {
-            write!(buffer, "{}=\"{}\"", p.0, p.1).unwrap();
+            write!(f, "{}=\"{}\"", p.0, p.1)?;
             if i < (lenpairs - 1) {
-                write!(buffer, ",").unwrap()
+                write!(f, ",")?
             }
         }
         if should_append_bracket {
-            write!(buffer, "}}").unwrap();
+            write!(f, "}}")?;
         }
 
         if !self.value.is_empty() {
-            write!(buffer, " {}", self.value).unwrap();
+            write!(f, " {}", self.value)?;
+        }
+        if let Some(timestamp) = &self.timestamp {
+            write!(f, " {}", timestamp)?;
         }
 
-        buffer
+        Ok(())
     }
 }
 
@@ -468,12 +1275,12 @@ prefix_rpc_duration_seconds_sum{key="value",keytwo="value2"} 1.7560473e+07
 prefix_rpc_duration_seconds_count{key="value",keytwo="value2"} 2693
 
 "#;
-        let mut ctx = Context::with_prefix(&input, "prefix_");
+        let mut ctx = Context::with_prefix(input, "prefix_");
         {
             let binding = &mut ctx;
             let output = binding.run();
 
-            assert_eq!(output.is_err(), false);
+            assert!(output.is_ok());
 
             let outstr = output.unwrap();
             assert_eq!(&outstr, &expect);
@@ -489,10 +1296,82 @@ metric_without_timestamp_and_labels 12.47"#;
                 "second_prefix_",
             );
 
-            assert_eq!(output.is_err(), false);
+            assert!(output.is_ok());
 
             let outstr = output.clone().unwrap();
             println!("Final: \n{}", &outstr);
         }
     }
+
+    #[test]
+    fn test_update_source() {
+        let first = "# Minimalistic line:\nup 1\n";
+        let second = "# Minimalistic line:\nup 0\n";
+        let mut ctx = Context::new("");
+        ctx.update_source("node-1", first).unwrap();
+        ctx.update_source("node-2", first).unwrap();
+        let output = ctx.update_source("node-1", second).unwrap();
+        assert_eq!(
+            output,
+            "# Minimalistic line:\nup 0\n\n# Minimalistic line:\nup 1\n\n"
+        );
+        assert!(ctx.remove_source("node-1"));
+        assert!(!ctx.remove_source("node-3"));
+        assert_eq!(ctx.refresh(), "# Minimalistic line:\nup 1\n\n");
+    }
+
+    #[test]
+    fn test_owned_context() {
+        fn store<T: Send + Sync + 'static>(value: T) -> T {
+            value
+        }
+        let input = String::from("# Minimalistic line:\nup 1\n");
+        let mut ctx = Context::new(&input);
+        ctx.run().unwrap();
+        let mut ctx = store(ctx.into_owned());
+        drop(input);
+
+        let buffer = String::from("# Minimalistic line:\nup 0\n");
+        ctx.combine_owned(&buffer, &[("zone".into(), "b".into())], "")
+            .unwrap();
+        drop(buffer);
+        let output = std::thread::spawn(move || ctx.result().to_string())
+            .join()
+            .unwrap();
+        assert_eq!(
+            output,
+            "# Minimalistic line:\nup 1\n\n# Minimalistic line:\nup{zone=\"b\"} 0\n\n"
+        );
+    }
+
+    #[test]
+    fn test_combine_batch() {
+        let inputs = [
+            "# HELP up Whether the target is up.\n# TYPE up gauge\nup 1\n",
+            "# HELP up Whether the target is up.\n# TYPE up gauge\nup 0\n",
+            "# Minimalistic line:\nrequests{path=\"/\"} 12\n",
+        ];
+        let pairs: Vec<[(String, String); 1]> = (0..inputs.len())
+            .map(|idx| [("instance".to_string(), format!("node-{}", idx))])
+            .collect();
+        let mut expect = Context::new("");
+        for (input, pairs) in inputs.iter().zip(&pairs) {
+            expect
+                .combine_with_prefix_and_pairs(input, pairs, "prefix_")
+                .unwrap();
+        }
+        let batch: Vec<BatchInput<'_, '_, &str>> = inputs
+            .iter()
+            .zip(&pairs)
+            .map(|(input, pairs)| (*input, &pairs[..], "prefix_"))
+            .collect();
+        let mut ctx = Context::new("");
+        assert_eq!(ctx.combine_batch(&batch).unwrap(), expect.result());
+
+        let mut ctx = Context::new("");
+        assert!(ctx
+            .combine_batch(&[("up 1\n", &[], "prefix_"), ("up{ 1", &[], "prefix_")])
+            .is_err());
+        assert_eq!(ctx.result(), "");
+    }
 }
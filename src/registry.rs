@@ -0,0 +1,181 @@
+//! Module containing a registry of concurrently updated sources.
+use std::sync::{Arc, RwLock};
+
+use crate::promerge::{Context, Error, Source};
+
+/// Registry merges named sources which are updated and
+/// rendered from multiple threads, e.g. by scraping tasks
+/// and an HTTP handler.
+///
+/// Updates parse and transform their input without holding
+/// any lock, then swap the snapshot of their slot. Rendering
+/// clones the list of snapshots and merges them outside of
+/// the lock, so it always sees a consistent view and never
+/// blocks updates for longer than that copy.
+#[derive(Debug)]
+pub struct Registry {
+    context: RwLock<Arc<Context<'static>>>,
+    slots: RwLock<Vec<(String, Arc<Source<'static>>)>>,
+}
+
+impl Registry {
+    /// Creates a registry merging sources with the
+    /// configuration of `context`, such as its aggregation,
+    /// relabeling and staleness. Inputs already merged into
+    /// `context` are not part of the registry.
+    pub fn new(context: Context<'static>) -> Self {
+        Self {
+            context: RwLock::new(Arc::new(context)),
+            slots: RwLock::new(Vec::new()),
+        }
+    }
+
+    /// Replaces the slot of the source `name` with `input`,
+    /// or adds it after all other sources.
+    pub fn update<N, S>(
+        &self,
+        name: N,
+        input: &str,
+        pairs: &[(String, String)],
+        prefix: S,
+    ) -> Result<(), Error>
+    where
+        N: Into<String>,
+        S: Into<String>,
+    {
+        let name = name.into();
+        let context = self.context();
+        let source =
+            Arc::new(context.prepare_owned(Some(name.clone()), input, pairs, prefix.into())?);
+        let mut slots = self.slots.write().unwrap();
+        match slots.iter_mut().find(|(n, _)| *n == name) {
+            Some(slot) => slot.1 = source,
+            None => slots.push((name, source)),
+        }
+        drop(slots);
+        context.collect_labels();
+        Ok(())
+    }
+
+    /// Adds an empty slot for the source `name` unless it
+    /// exists, fixing its position in the merged output before
+    /// its first update.
+    pub fn register<N: Into<String>>(&self, name: N) {
+        let name = name.into();
+        let mut slots = self.slots.write().unwrap();
+        if !slots.iter().any(|(n, _)| *n == name) {
+            let source = Source::empty(name.clone());
+            slots.push((name, Arc::new(source)));
+        }
+    }
+
+    /// Replaces the configuration of the registry by `context`
+    /// and its sources by `names`, in that order. Sources that
+    /// remain keep their snapshot, prepared with the previous
+    /// configuration, until their next update. Snapshots that
+    /// only kept their rendering are emitted as is meanwhile.
+    pub fn reconfigure<I, N>(&self, context: Context<'static>, names: I)
+    where
+        I: IntoIterator<Item = N>,
+        N: Into<String>,
+    {
+        let mut slots = self.slots.write().unwrap();
+        let mut previous = std::mem::take(&mut *slots);
+        for name in names {
+            let name = name.into();
+            let source = match previous.iter().position(|(n, _)| *n == name) {
+                Some(idx) => previous.swap_remove(idx).1,
+                None => Arc::new(Source::empty(name.clone())),
+            };
+            slots.push((name, source));
+        }
+        *self.context.write().unwrap() = Arc::new(context);
+    }
+
+    fn context(&self) -> Arc<Context<'static>> {
+        self.context.read().unwrap().clone()
+    }
+
+    /// Removes the source `name`, returning whether it existed.
+    pub fn remove(&self, name: &str) -> bool {
+        let mut slots = self.slots.write().unwrap();
+        let len = slots.len();
+        slots.retain(|(n, _)| n != name);
+        let removed = slots.len() != len;
+        drop(slots);
+        self.context().collect_labels();
+        removed
+    }
+
+    /// Returns the names of all sources in merge order.
+    pub fn sources(&self) -> Vec<String> {
+        let slots = self.slots.read().unwrap();
+        slots.iter().map(|(name, _)| name.clone()).collect()
+    }
+
+    /// Returns the merged output of the current snapshots.
+    pub fn render(&self) -> String {
+        let snapshot: Vec<Arc<Source<'static>>> = {
+            let slots = self.slots.read().unwrap();
+            slots.iter().map(|(_, source)| source.clone()).collect()
+        };
+        let sources: Vec<&Source<'static>> = snapshot.iter().map(|s| s.as_ref()).collect();
+        let mut output = String::new();
+        self.context().compose(&sources, &mut output);
+        output
+    }
+
+    /// Writes the merged output of the current snapshots into `sink`.
+    pub fn write_io<W: std::io::Write>(&self, sink: &mut W) -> std::io::Result<()> {
+        sink.write_all(self.render().as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::aggregate::Aggregation;
+
+    #[test]
+    fn test_registry() {
+        fn shared<T: Send + Sync>(value: T) -> Arc<T> {
+            Arc::new(value)
+        }
+        let mut context = Context::new("");
+        context.set_aggregation(Aggregation::sum_without(["instance"]));
+        let registry = shared(Registry::new(context));
+
+        let workers: Vec<_> = (0..4)
+            .map(|idx| {
+                let registry = registry.clone();
+                std::thread::spawn(move || {
+                    for round in 0..=10 {
+                        let input = format!(
+                            "# TYPE requests_total counter\nrequests_total{{code=\"200\"}} {}\n",
+                            round
+                        );
+                        let pairs = [("instance".to_string(), format!("node-{}", idx))];
+                        registry
+                            .update(format!("node-{}", idx), &input, &pairs, "")
+                            .unwrap();
+                        assert!(registry.render().starts_with("# TYPE requests_total"));
+                    }
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        assert_eq!(registry.sources().len(), 4);
+        assert_eq!(
+            registry.render(),
+            "# TYPE requests_total counter\nrequests_total{code=\"200\"} 40\n\n"
+        );
+        assert!(registry.remove("node-0"));
+        assert!(registry.update("node-1", "up{ 1", &[], "").is_err());
+        assert_eq!(
+            registry.render(),
+            "# TYPE requests_total counter\nrequests_total{code=\"200\"} 30\n\n"
+        );
+    }
+}
@@ -0,0 +1,345 @@
+//! Module containing Prometheus-style relabeling of parsed series.
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+use md5::{Digest, Md5};
+use regex::Regex;
+
+use crate::intern::Label;
+use crate::promerge::{Segment, Value};
+
+type Labels = Vec<(String, String)>;
+
+/// Name of the label holding the metric family name.
+pub const NAME_LABEL: &str = "__name__";
+
+/// Action performed by a relabeling rule.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Replace,
+    Keep,
+    Drop,
+    LabelMap,
+    LabelDrop,
+    LabelKeep,
+    HashMod,
+    Lowercase,
+    Uppercase,
+    KeepEqual,
+    DropEqual,
+}
+
+/// RelabelConfig is a single relabeling rule following the
+/// semantics of Prometheus `relabel_configs`.
+///
+/// Source label values are joined by `separator` and matched
+/// against the fully anchored `regex`. Defaults are the same
+/// as in Prometheus: separator `;`, regex `(.*)` and
+/// replacement `$1`.
+#[derive(Debug, Clone)]
+pub struct RelabelConfig {
+    action: Action,
+    source_labels: Vec<String>,
+    separator: String,
+    regex: Regex,
+    modulus: u64,
+    target_label: String,
+    replacement: String,
+}
+
+impl RelabelConfig {
+    pub fn new(action: Action) -> Self {
+        Self {
+            action,
+            source_labels: Vec::new(),
+            separator: ";".into(),
+            regex: anchored("(.*)").unwrap(),
+            modulus: 0,
+            target_label: String::new(),
+            replacement: "$1".into(),
+        }
+    }
+
+    pub fn with_source_labels<I, S>(mut self, labels: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.source_labels = labels.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn with_separator<S: Into<String>>(mut self, separator: S) -> Self {
+        self.separator = separator.into();
+        self
+    }
+
+    pub fn with_regex(mut self, regex: &str) -> Result<Self, regex::Error> {
+        self.regex = anchored(regex)?;
+        Ok(self)
+    }
+
+    pub fn with_modulus(mut self, modulus: u64) -> Self {
+        self.modulus = modulus;
+        self
+    }
+
+    pub fn with_target_label<S: Into<String>>(mut self, label: S) -> Self {
+        self.target_label = label.into();
+        self
+    }
+
+    pub fn with_replacement<S: Into<String>>(mut self, replacement: S) -> Self {
+        self.replacement = replacement.into();
+        self
+    }
+
+    /// Applies the rule to `labels`, returning `false`
+    /// when the series must be dropped.
+    fn apply(&self, labels: &mut Labels) -> bool {
+        let value = self
+            .source_labels
+            .iter()
+            .map(|l| get(labels, l).unwrap_or(""))
+            .collect::<Vec<&str>>()
+            .join(&self.separator);
+        match self.action {
+            Action::Keep => return self.regex.is_match(&value),
+            Action::Drop => return !self.regex.is_match(&value),
+            Action::KeepEqual => return get(labels, &self.target_label).unwrap_or("") == value,
+            Action::DropEqual => return get(labels, &self.target_label).unwrap_or("") != value,
+            Action::Replace => {
+                if let Some(captures) = self.regex.captures(&value) {
+                    let mut target = String::new();
+                    let mut result = String::new();
+                    captures.expand(&self.target_label, &mut target);
+                    captures.expand(&self.replacement, &mut result);
+                    if is_valid_label_name(&target) {
+                        set(labels, &target, result);
+                    }
+                }
+            }
+            Action::Lowercase => set(labels, &self.target_label, value.to_lowercase()),
+            Action::Uppercase => set(labels, &self.target_label, value.to_uppercase()),
+            Action::HashMod => {
+                let hash = Md5::digest(value.as_bytes());
+                let mut tail = [0u8; 8];
+                tail.copy_from_slice(&hash[8..]);
+                let modulus = u64::from_be_bytes(tail) % self.modulus.max(1);
+                set(labels, &self.target_label, modulus.to_string());
+            }
+            Action::LabelMap => {
+                for (key, value) in labels.clone() {
+                    if self.regex.is_match(&key) {
+                        let target = self.regex.replace_all(&key, self.replacement.as_str());
+                        set(labels, &target, value);
+                    }
+                }
+            }
+            Action::LabelDrop => labels.retain(|(k, _)| !self.regex.is_match(k)),
+            Action::LabelKeep => labels.retain(|(k, _)| self.regex.is_match(k)),
+        }
+        true
+    }
+}
+
+/// Compiles `regex` to only match whole strings.
+pub(crate) fn anchored(regex: &str) -> Result<Regex, regex::Error> {
+    Regex::new(&format!("^(?:{})$", regex))
+}
+
+fn get<'l>(labels: &'l Labels, name: &str) -> Option<&'l str> {
+    labels
+        .iter()
+        .find(|(k, _)| k == name)
+        .map(|(_, v)| v.as_str())
+}
+
+/// Sets `name` to `value`, removing the label when `value` is empty.
+fn set(labels: &mut Labels, name: &str, value: String) {
+    let position = labels.iter().position(|(k, _)| k == name);
+    match (position, value.is_empty()) {
+        (Some(idx), true) => {
+            labels.remove(idx);
+        }
+        (Some(idx), false) => labels[idx].1 = value,
+        (None, true) => {}
+        (None, false) => labels.push((name.into(), value)),
+    }
+}
+
+pub(crate) fn is_valid_label_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Runs all `configs` over the labels of a series, where
+/// `__name__` holds `name`. Returns the new name of the
+/// series and its labels, or `None` when it was dropped.
+fn apply_all<'a>(
+    configs: &[RelabelConfig],
+    name: &str,
+    pairs: &[(Label<'a>, Label<'a>)],
+) -> Option<(String, Labels)> {
+    let mut labels: Labels = pairs
+        .iter()
+        .filter(|(k, _)| !k.is_empty())
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+    labels.push((NAME_LABEL.into(), name.into()));
+    for config in configs {
+        if !config.apply(&mut labels) {
+            return None;
+        }
+    }
+    let name = match labels.iter().position(|(k, _)| k == NAME_LABEL) {
+        Some(idx) => labels.remove(idx).1,
+        None => name.into(),
+    };
+    Some((name, labels))
+}
+
+/// Returns `labels` as the label pairs of a `_sum` or
+/// `_count` segment.
+pub(crate) fn to_pairs<'a>(labels: Labels) -> Vec<(Label<'a>, Label<'a>)> {
+    labels
+        .into_iter()
+        .map(|(k, v)| (k.into(), v.into()))
+        .collect()
+}
+
+/// Returns `labels` as the label pairs of a sample, which
+/// holds an empty pair when there are none, as parsed ones do.
+pub(crate) fn to_sample_pairs<'a>(labels: Labels) -> Vec<(Label<'a>, Label<'a>)> {
+    if labels.is_empty() {
+        return vec![("".into(), "".into())];
+    }
+    to_pairs(labels)
+}
+
+/// Relabels every series of `values`. `__name__` holds the
+/// prefixed family name, shared by the `_bucket`, `_sum` and
+/// `_count` lines. Series whose name was rewritten are moved
+/// to the family of that name, shared by all families of
+/// `values`, and families left without series are removed.
+pub(crate) fn relabel<'a>(values: Vec<Value<'a>>, configs: &[RelabelConfig]) -> Vec<Value<'a>> {
+    if configs.is_empty() {
+        return values;
+    }
+    let mut families: Vec<Value<'a>> = Vec::with_capacity(values.len());
+    let mut index: HashMap<String, usize> = HashMap::new();
+    for value in values {
+        let name = format!(
+            "{}{}",
+            value.prefix.as_deref().unwrap_or(""),
+            value.family_name()
+        );
+        let mut slot = |target: String, families: &mut Vec<Value<'a>>| -> usize {
+            *index.entry(target.clone()).or_insert_with(|| {
+                let mut family = Value::new(value.key.clone());
+                family.prefix = value.prefix.clone();
+                family.description = value.description.clone();
+                if target != name {
+                    family.key = target.clone();
+                    family.prefix = None;
+                    if let Some(desc) = &mut family.description {
+                        if !desc.name.is_empty() {
+                            desc.name = Cow::Owned(target);
+                        }
+                    }
+                }
+                families.push(family);
+                families.len() - 1
+            })
+        };
+
+        for (pairs, sample) in value.pairs.iter().zip(value.values.iter()) {
+            let Some((target, labels)) = apply_all(configs, &name, pairs) else {
+                continue;
+            };
+            let idx = slot(target, &mut families);
+            families[idx].pairs.push(to_sample_pairs(labels));
+            families[idx].values.push(sample.clone());
+        }
+        let segments = value.sum.iter().map(|s| (true, s));
+        for (is_sum, segment) in segments.chain(value.count.iter().map(|s| (false, s))) {
+            let Some((target, labels)) = apply_all(configs, &name, &segment.pairs) else {
+                continue;
+            };
+            let idx = slot(target, &mut families);
+            let segment = Segment {
+                value: segment.value.clone(),
+                timestamp: segment.timestamp.clone(),
+                pairs: to_pairs(labels),
+            };
+            if is_sum {
+                families[idx].sum.push(segment);
+            } else {
+                families[idx].count.push(segment);
+            }
+        }
+    }
+    families
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::promerge::Context;
+
+    #[test]
+    fn test_relabel() {
+        let input = r#"# HELP http_requests_total The total number of HTTP requests.
+# TYPE http_requests_total counter
+http_requests_total{method="post",code="200",path="/a"} 1027
+http_requests_total{method="get",code="400",path="/b"} 3
+
+# HELP go_gc_duration_seconds A summary of the GC invocation durations.
+# TYPE go_gc_duration_seconds summary
+go_gc_duration_seconds{quantile="0.5"} 0.01
+go_gc_duration_seconds_sum 1.5
+go_gc_duration_seconds_count 20
+"#;
+        let expect = r#"# HELP api_requests_total The total number of HTTP requests.
+# TYPE api_requests_total counter
+api_requests_total{method="POST",code="200"} 1027
+
+"#;
+        let configs = vec![
+            RelabelConfig::new(Action::Drop)
+                .with_source_labels([NAME_LABEL])
+                .with_regex("go_gc_.*")
+                .unwrap(),
+            RelabelConfig::new(Action::Drop)
+                .with_source_labels(["code"])
+                .with_regex("4..")
+                .unwrap(),
+            RelabelConfig::new(Action::LabelDrop)
+                .with_regex("path")
+                .unwrap(),
+            RelabelConfig::new(Action::Uppercase)
+                .with_source_labels(["method"])
+                .with_target_label("method"),
+            RelabelConfig::new(Action::Replace)
+                .with_source_labels([NAME_LABEL])
+                .with_regex("http_(.*)")
+                .unwrap()
+                .with_target_label(NAME_LABEL)
+                .with_replacement("api_$1"),
+        ];
+        let mut ctx = Context::new(input);
+        ctx.set_relabel_configs(configs);
+        assert_eq!(ctx.run().unwrap(), expect);
+
+        // Families renamed to the same name become one.
+        let mut ctx = Context::new("# TYPE a gauge\na 1\n# TYPE b gauge\nb{x=\"y\"} 2\n");
+        ctx.set_relabel_configs(vec![RelabelConfig::new(Action::Replace)
+            .with_source_labels([NAME_LABEL])
+            .with_regex("a|b")
+            .unwrap()
+            .with_target_label(NAME_LABEL)
+            .with_replacement("c")]);
+        assert_eq!(ctx.run().unwrap(), "# TYPE c gauge\nc 1\nc{x=\"y\"} 2\n\n");
+    }
+}
@@ -0,0 +1,152 @@
+//! Module containing rule based renaming of metric families.
+use std::borrow::Cow;
+
+use regex::Regex;
+
+use crate::promerge::Value;
+use crate::relabel::anchored;
+
+/// RenameRule rewrites a single family name.
+#[derive(Debug, Clone)]
+enum RenameRule {
+    Exact(String, String),
+    Regex(Regex, String),
+    StripPrefix(String),
+    AddSuffix(String),
+}
+
+/// Rename rewrites family names with an ordered list of rules,
+/// each one seeing the name produced by the previous one.
+///
+/// Rules apply to the family name before the prefix of the
+/// input is added. Since `# HELP`/`# TYPE` lines and the
+/// `_bucket`, `_sum` and `_count` lines are all derived from
+/// the family name, they follow the rename.
+#[derive(Debug, Clone, Default)]
+pub struct Rename {
+    rules: Vec<RenameRule>,
+}
+
+impl Rename {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Renames the family called exactly `from` to `to`.
+    pub fn exact<F, T>(mut self, from: F, to: T) -> Self
+    where
+        F: Into<String>,
+        T: Into<String>,
+    {
+        self.rules.push(RenameRule::Exact(from.into(), to.into()));
+        self
+    }
+
+    /// Renames families fully matching `pattern` to `replacement`,
+    /// which may refer to capture groups as `$1` or `${name}`.
+    pub fn regex<S: Into<String>>(
+        mut self,
+        pattern: &str,
+        replacement: S,
+    ) -> Result<Self, regex::Error> {
+        let regex = anchored(pattern)?;
+        self.rules
+            .push(RenameRule::Regex(regex, replacement.into()));
+        Ok(self)
+    }
+
+    /// Removes `prefix` from family names starting with it.
+    pub fn strip_prefix<S: Into<String>>(mut self, prefix: S) -> Self {
+        self.rules.push(RenameRule::StripPrefix(prefix.into()));
+        self
+    }
+
+    /// Appends `suffix` to family names not already ending with it.
+    pub fn add_suffix<S: Into<String>>(mut self, suffix: S) -> Self {
+        self.rules.push(RenameRule::AddSuffix(suffix.into()));
+        self
+    }
+
+    /// Returns the new name of the family `name`, if any rule applied.
+    pub fn apply(&self, name: &str) -> Option<String> {
+        let mut result: Cow<'_, str> = Cow::Borrowed(name);
+        for rule in &self.rules {
+            let renamed = match rule {
+                RenameRule::Exact(from, to) if from == result.as_ref() => Some(to.clone()),
+                RenameRule::Regex(regex, replacement) => regex.captures(&result).map(|captures| {
+                    let mut buffer = String::new();
+                    captures.expand(replacement, &mut buffer);
+                    buffer
+                }),
+                RenameRule::StripPrefix(prefix) => {
+                    result.strip_prefix(prefix.as_str()).map(Into::into)
+                }
+                RenameRule::AddSuffix(suffix) if !result.ends_with(suffix.as_str()) => {
+                    Some(format!("{}{}", result, suffix))
+                }
+                _ => None,
+            };
+            if let Some(renamed) = renamed {
+                result = Cow::Owned(renamed);
+            }
+        }
+        match result {
+            Cow::Owned(renamed) if renamed != name => Some(renamed),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn rename(&self, value: &mut Value<'_>) {
+        let old = value.family_name().to_string();
+        let Some(name) = self.apply(&old) else {
+            return;
+        };
+        if let Some(rest) = value.key.strip_prefix(old.as_str()) {
+            value.key = format!("{}{}", name, rest);
+        }
+        if let Some(desc) = &mut value.description {
+            if !desc.name.is_empty() {
+                desc.name = Cow::Owned(name);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::promerge::Context;
+
+    #[test]
+    fn test_rename() {
+        let input = r#"# HELP node_http_request_duration_seconds A histogram of the request duration.
+# TYPE node_http_request_duration_seconds histogram
+node_http_request_duration_seconds_bucket{le="0.05"} 24054
+node_http_request_duration_seconds_bucket{le="+Inf"} 144320
+node_http_request_duration_seconds_sum 53423
+node_http_request_duration_seconds_count 144320
+
+# Minimalistic line:
+node_requests 12
+"#;
+        let expect = r#"# HELP prefix_http_latency_seconds A histogram of the request duration.
+# TYPE prefix_http_latency_seconds histogram
+prefix_http_latency_seconds_bucket{le="0.05"} 24054
+prefix_http_latency_seconds_bucket{le="+Inf"} 144320
+prefix_http_latency_seconds_sum 53423
+prefix_http_latency_seconds_count 144320
+
+# Minimalistic line:
+prefix_requests_total 12
+
+"#;
+        let rename = Rename::new()
+            .strip_prefix("node_")
+            .regex("http_request_duration_(.+)", "http_latency_$1")
+            .unwrap()
+            .exact("requests", "requests_total");
+        let mut ctx = Context::with_prefix(input, "prefix_");
+        ctx.set_rename(rename);
+        assert_eq!(ctx.run().unwrap(), expect);
+    }
+}
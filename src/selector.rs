@@ -0,0 +1,242 @@
+//! Module containing PromQL-style series selection.
+use std::borrow::Cow;
+
+use regex::Regex;
+
+use crate::intern::Label;
+use crate::promerge::Value;
+use crate::relabel::{anchored, NAME_LABEL};
+use crate::*;
+
+#[derive(Parse)]
+#[grammar = "./selector.pest"]
+struct SelectorParser;
+
+/// Operator comparing a label value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MatchOp {
+    Equal,
+    NotEqual,
+    RegexMatch,
+    RegexNoMatch,
+}
+
+/// Matcher compares the value of a single label. A
+/// missing label is matched as an empty value.
+#[derive(Debug, Clone)]
+pub struct Matcher {
+    pub name: String,
+    pub op: MatchOp,
+    pub value: String,
+    regex: Option<Regex>,
+}
+
+/// Selector keeps series matching all of its matchers, e.g.
+/// `http_requests_total{job="api",code=~"5.."}`.
+///
+/// The metric name is matched against the prefixed family
+/// name, shared by the `_bucket`, `_sum` and `_count` lines.
+#[derive(Debug, Clone, Default)]
+pub struct Selector {
+    matchers: Vec<Matcher>,
+}
+
+/// Error returned when a selector can not be parsed.
+#[derive(Debug)]
+pub enum Error {
+    Syntax(Box<pest::error::Error<Rule>>),
+    Regex(regex::Error),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Syntax(err) => write!(f, "invalid selector: {}", err),
+            Error::Regex(err) => write!(f, "invalid selector regex: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl Matcher {
+    pub fn new<N, V>(name: N, op: MatchOp, value: V) -> Result<Self, Error>
+    where
+        N: Into<String>,
+        V: Into<String>,
+    {
+        let value = value.into();
+        let regex = match op {
+            MatchOp::RegexMatch | MatchOp::RegexNoMatch => {
+                Some(anchored(&value).map_err(Error::Regex)?)
+            }
+            _ => None,
+        };
+        Ok(Self {
+            name: name.into(),
+            op,
+            value,
+            regex,
+        })
+    }
+
+    pub fn matches(&self, value: &str) -> bool {
+        match (&self.op, &self.regex) {
+            (MatchOp::Equal, _) => self.value == value,
+            (MatchOp::NotEqual, _) => self.value != value,
+            (MatchOp::RegexMatch, Some(r)) => r.is_match(value),
+            (MatchOp::RegexNoMatch, Some(r)) => !r.is_match(value),
+            _ => false,
+        }
+    }
+}
+
+impl Selector {
+    pub fn new(matchers: Vec<Matcher>) -> Self {
+        Self { matchers }
+    }
+
+    /// Parses a selector such as `up`, `{job="api"}` or
+    /// `http_requests_total{code=~"5..",method!="get"}`.
+    pub fn parse(input: &str) -> Result<Self, Error> {
+        let root = SelectorParser::parse(Rule::selector, input)
+            .map_err(|err| Error::Syntax(Box::new(err)))?
+            .next()
+            .unwrap();
+        let mut matchers: Vec<Matcher> = Vec::new();
+        for token in root.into_inner() {
+            match token.as_rule() {
+                Rule::name => {
+                    matchers.push(Matcher::new(NAME_LABEL, MatchOp::Equal, token.as_str())?);
+                }
+                Rule::matchers => {
+                    for matcher in token.into_inner() {
+                        let mut inner = matcher.into_inner();
+                        let name = inner.next().unwrap().as_str();
+                        let op = match inner.next().unwrap().as_str() {
+                            "!=" => MatchOp::NotEqual,
+                            "=~" => MatchOp::RegexMatch,
+                            "!~" => MatchOp::RegexNoMatch,
+                            _ => MatchOp::Equal,
+                        };
+                        let value = inner.next().unwrap().into_inner().next().unwrap();
+                        matchers.push(Matcher::new(name, op, unescape(value.as_str()))?);
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(Self { matchers })
+    }
+
+    /// Returns whether the series `name` with `pairs` matches.
+    pub fn matches(&self, name: &str, pairs: &[(Label<'_>, Label<'_>)]) -> bool {
+        self.matchers.iter().all(|m| {
+            if m.name == NAME_LABEL {
+                return m.matches(name);
+            }
+            let value = pairs
+                .iter()
+                .find(|(k, _)| **k == m.name)
+                .map_or(Cow::Borrowed(""), |(_, v)| unescape(v));
+            m.matches(&value)
+        })
+    }
+
+    /// Returns the families of `values` reduced to matching
+    /// series, leaving out families without any.
+    pub fn select<'v, 'a: 'v, I>(&self, values: I) -> Vec<Value<'a>>
+    where
+        I: IntoIterator<Item = &'v Value<'a>>,
+    {
+        values
+            .into_iter()
+            .filter_map(|v| self.retain(v.clone()))
+            .collect()
+    }
+
+    pub(crate) fn retain<'a>(&self, mut value: Value<'a>) -> Option<Value<'a>> {
+        let name = format!(
+            "{}{}",
+            value.prefix.as_deref().unwrap_or(""),
+            value.family_name()
+        );
+        let mut samples = std::mem::take(&mut value.values).into_iter();
+        let pairs = std::mem::take(&mut value.pairs);
+        for pairs in pairs {
+            let sample = samples.next();
+            if self.matches(&name, &pairs) {
+                value.pairs.push(pairs);
+                value.values.extend(sample);
+            }
+        }
+        value.sum.retain(|s| self.matches(&name, &s.pairs));
+        value.count.retain(|s| self.matches(&name, &s.pairs));
+        if value.values.is_empty() && value.sum.is_empty() && value.count.is_empty() {
+            return None;
+        }
+        Some(value)
+    }
+}
+
+/// Resolves the escape sequences of a quoted label value.
+pub(crate) fn unescape(input: &str) -> Cow<'_, str> {
+    if !input.contains('\\') {
+        return Cow::Borrowed(input);
+    }
+    let mut buffer = String::with_capacity(input.len());
+    let mut chars = input.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            buffer.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => buffer.push('\n'),
+            Some(c) => buffer.push(c),
+            None => buffer.push('\\'),
+        }
+    }
+    Cow::Owned(buffer)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::promerge::Context;
+
+    #[test]
+    fn test_select() {
+        let input = r#"# HELP http_requests_total The total number of HTTP requests.
+# TYPE http_requests_total counter
+http_requests_total{method="post",code="200"} 1027
+http_requests_total{method="post",code="503"} 3
+http_requests_total{method="get",code="500"} 7
+
+# Escaping in label values:
+msdos_file_access_time_seconds{path="C:\\DIR\\FILE.TXT",error="Cannot find file:\n\"FILE.TXT\""} 1.458255915e9
+"#;
+        let expect = r#"# HELP http_requests_total The total number of HTTP requests.
+# TYPE http_requests_total counter
+http_requests_total{method="post",code="503"} 3
+
+"#;
+        let selector = Selector::parse(r#"{code=~"5..", method!="get"}"#).unwrap();
+        let mut ctx = Context::new(input);
+        ctx.set_selector(selector);
+        assert_eq!(ctx.run().unwrap(), expect);
+
+        // families are not kept by default, which `values` tells.
+        let mut ctx = Context::new(input);
+        ctx.run().unwrap();
+        assert!(ctx.values().is_none());
+        let mut ctx = Context::new(input);
+        ctx.set_keep_values(true);
+        ctx.run().unwrap();
+        let selector = Selector::parse(r#"{path="C:\\DIR\\FILE.TXT"}"#).unwrap();
+        let selected = selector.select(ctx.values().unwrap());
+        assert_eq!(selected.len(), 1);
+        assert_eq!(selected[0].family_name(), "msdos_file_access_time_seconds");
+        assert!(Selector::parse(r#"{code=~"5.."#).is_err());
+    }
+}
@@ -0,0 +1,437 @@
+//! Module containing the HTTP aggregator behind `promerge serve`.
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+
+use serde::Deserialize;
+
+use crate::config::{Config, OutputFormat};
+use crate::promerge::Context;
+use crate::registry::Registry;
+
+/// Target is an upstream endpoint exposing metrics.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Target {
+    #[serde(default)]
+    pub name: Option<String>,
+    pub url: String,
+    #[serde(default)]
+    pub prefix: String,
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+}
+
+/// ServeConfig describes the upstream targets and how the
+/// merged metrics are served.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ServeConfig {
+    #[serde(default = "default_listen")]
+    pub listen: String,
+    #[serde(default = "default_interval")]
+    pub interval_secs: u64,
+    #[serde(default = "default_timeout")]
+    pub timeout_secs: u64,
+    pub targets: Vec<Target>,
+    #[serde(default)]
+    pub format: OutputFormat,
+}
+
+impl Target {
+    /// Returns the name of the source merged from the target,
+    /// defaulting to its url.
+    pub fn name(&self) -> &str {
+        self.name.as_deref().unwrap_or(&self.url)
+    }
+}
+
+pub(crate) fn default_listen() -> String {
+    "0.0.0.0:9100".into()
+}
+
+pub(crate) fn default_interval() -> u64 {
+    15
+}
+
+pub(crate) fn default_timeout() -> u64 {
+    10
+}
+
+impl ServeConfig {
+    pub fn from_json(input: &str) -> Result<Self, serde_json::Error> {
+        serde_json::from_str(input)
+    }
+}
+
+/// Error returned when scraping a target fails.
+#[derive(Debug)]
+pub enum ScrapeError {
+    Url(String),
+    Io(std::io::Error),
+    Status(String),
+    Parse(crate::promerge::Error),
+}
+
+impl std::fmt::Display for ScrapeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ScrapeError::Url(url) => write!(f, "unsupported url {:?}", url),
+            ScrapeError::Io(err) => write!(f, "request failed: {}", err),
+            ScrapeError::Status(status) => write!(f, "unexpected status {:?}", status),
+            ScrapeError::Parse(err) => write!(f, "invalid exposition: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for ScrapeError {}
+
+impl From<std::io::Error> for ScrapeError {
+    fn from(err: std::io::Error) -> Self {
+        ScrapeError::Io(err)
+    }
+}
+
+/// Fetches the body of a plain `http://` `url`.
+pub fn fetch(url: &str, timeout: Duration) -> Result<String, ScrapeError> {
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or_else(|| ScrapeError::Url(url.into()))?;
+    let (host, path) = match rest.find('/') {
+        Some(idx) => (&rest[..idx], &rest[idx..]),
+        None => (rest, "/"),
+    };
+    let address = if host.contains(':') {
+        host.to_string()
+    } else {
+        format!("{}:80", host)
+    };
+    let address = std::net::ToSocketAddrs::to_socket_addrs(&address)?
+        .next()
+        .ok_or_else(|| ScrapeError::Url(url.into()))?;
+    let mut stream = TcpStream::connect_timeout(&address, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    let request = format!(
+        "GET {} HTTP/1.0\r\nHost: {}\r\nAccept: text/plain\r\n\r\n",
+        path, host
+    );
+    stream.write_all(request.as_bytes())?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .ok_or_else(|| ScrapeError::Status("".into()))?;
+    let status = head.lines().next().unwrap_or("");
+    if status.split(' ').nth(1) != Some("200") {
+        return Err(ScrapeError::Status(status.into()));
+    }
+    Ok(body.to_string())
+}
+
+/// Server scrapes all targets of its configuration into a
+/// shared registry and serves the merged output on `/metrics`.
+pub struct Server {
+    shared: Arc<Shared>,
+    registry: Arc<Registry>,
+    listener: TcpListener,
+    watch: Option<PathBuf>,
+}
+
+impl Server {
+    /// Binds the listen address of `config`, merging sources
+    /// with the configuration of `context`.
+    pub fn bind(config: ServeConfig, context: Context<'static>) -> std::io::Result<Self> {
+        let listener = TcpListener::bind(&config.listen)?;
+        let registry = Registry::new(context);
+        for target in &config.targets {
+            registry.register(target.name());
+        }
+        Ok(Self {
+            shared: Arc::new(Shared {
+                config: RwLock::new(config),
+                scraping: Mutex::new(()),
+            }),
+            registry: Arc::new(registry),
+            listener,
+            watch: None,
+        })
+    }
+
+    /// Reloads the configuration file at `path` while running,
+    /// whenever it is modified or, on unix, the process
+    /// receives `SIGHUP`.
+    pub fn watch<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.watch = Some(path.into());
+        self
+    }
+
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub fn registry(&self) -> &Arc<Registry> {
+        &self.registry
+    }
+
+    /// Scrapes all targets concurrently once. Targets that
+    /// fail keep their previous contribution.
+    pub fn scrape(&self) -> Vec<(String, Result<(), ScrapeError>)> {
+        self.shared.scrape(&self.registry)
+    }
+
+    /// Loads and validates the configuration file at `path`,
+    /// then applies it once no scrape is in progress. On error
+    /// the current configuration is kept.
+    ///
+    /// Targets that remain keep their contribution to the
+    /// merged output until they are scraped again. The listen
+    /// address can not be changed.
+    pub fn reload<P: AsRef<Path>>(&self, path: P) -> Result<(), crate::config::Error> {
+        self.shared.reload(path.as_ref(), &self.registry)
+    }
+
+    /// Scrapes on schedule in the background and serves
+    /// requests. Connections failing to be accepted are logged
+    /// and skipped, and the ones accepted time out after the
+    /// configured scrape timeout.
+    pub fn run(self) -> std::io::Result<()> {
+        if let Some(path) = self.watch.clone() {
+            let changed = Arc::new(AtomicBool::new(false));
+            #[cfg(unix)]
+            signal_hook::flag::register(signal_hook::consts::SIGHUP, changed.clone())?;
+            let shared = self.shared.clone();
+            let registry = self.registry.clone();
+            std::thread::spawn(move || {
+                let mut last = modified(&path);
+                loop {
+                    std::thread::sleep(Duration::from_secs(1));
+                    let current = modified(&path);
+                    if !changed.swap(false, Ordering::Relaxed) && current == last {
+                        continue;
+                    }
+                    last = current;
+                    match shared.reload(&path, &registry) {
+                        Ok(()) => eprintln!("promerge: reloaded {}", path.display()),
+                        Err(err) => eprintln!(
+                            "promerge: keeping previous config, reloading {} failed: {}",
+                            path.display(),
+                            err
+                        ),
+                    }
+                }
+            });
+        }
+        let shared = self.shared.clone();
+        let registry = self.registry.clone();
+        std::thread::spawn(move || loop {
+            for (url, result) in shared.scrape(&registry) {
+                if let Err(err) = result {
+                    eprintln!("promerge: scraping {} failed: {}", url, err);
+                }
+            }
+            let interval = shared.config.read().unwrap().interval_secs;
+            std::thread::sleep(Duration::from_secs(interval));
+        });
+        for stream in self.listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    eprintln!("promerge: accepting connection failed: {}", err);
+                    // e.g. out of file descriptors, give them time to close.
+                    std::thread::sleep(Duration::from_millis(100));
+                    continue;
+                }
+            };
+            let registry = self.registry.clone();
+            let (format, timeout) = {
+                let config = self.shared.config.read().unwrap();
+                (config.format, Duration::from_secs(config.timeout_secs))
+            };
+            std::thread::spawn(move || {
+                if let Err(err) = respond(stream, &registry, format, timeout) {
+                    eprintln!("promerge: serving request failed: {}", err);
+                }
+            });
+        }
+        Ok(())
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Shared holds the configuration of a running server. Scrapes
+/// and reloads are serialized, so that a scrape started with the
+/// previous configuration can not add back a removed target.
+struct Shared {
+    config: RwLock<ServeConfig>,
+    scraping: Mutex<()>,
+}
+
+impl Shared {
+    fn scrape(&self, registry: &Registry) -> Vec<(String, Result<(), ScrapeError>)> {
+        let _guard = self.scraping.lock().unwrap();
+        let config = self.config.read().unwrap().clone();
+        scrape_all(&config, registry)
+    }
+
+    fn reload(&self, path: &Path, registry: &Registry) -> Result<(), crate::config::Error> {
+        let loaded = Config::load(path)?;
+        let context = loaded.context()?;
+        let mut next = loaded.serve_config()?;
+        let _guard = self.scraping.lock().unwrap();
+        let mut config = self.config.write().unwrap();
+        next.listen = config.listen.clone();
+        registry.reconfigure(context, next.targets.iter().map(Target::name));
+        *config = next;
+        Ok(())
+    }
+}
+
+fn scrape_all(config: &ServeConfig, registry: &Registry) -> Vec<(String, Result<(), ScrapeError>)> {
+    let timeout = Duration::from_secs(config.timeout_secs);
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = config
+            .targets
+            .iter()
+            .map(|target| {
+                scope.spawn(move || {
+                    let body = fetch(&target.url, timeout)?;
+                    let pairs: Vec<(String, String)> = target
+                        .labels
+                        .iter()
+                        .map(|(k, v)| (k.clone(), v.clone()))
+                        .collect();
+                    registry
+                        .update(target.name(), &body, &pairs, target.prefix.as_str())
+                        .map_err(ScrapeError::Parse)
+                })
+            })
+            .collect();
+        config
+            .targets
+            .iter()
+            .zip(handles)
+            .map(|(target, handle)| (target.url.clone(), handle.join().unwrap()))
+            .collect()
+    })
+}
+
+fn respond(
+    stream: TcpStream,
+    registry: &Registry,
+    format: OutputFormat,
+    timeout: Duration,
+) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    let mut reader = BufReader::new(stream);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+    }
+    let mut stream = reader.into_inner();
+    let mut parts = request.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            ("200 OK", format.render(&registry.render()).into_owned())
+        }
+        _ => ("404 Not Found", "not found\n".to_string()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        format.content_type(),
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Serves `body` on every request of a local listener.
+    fn stub(body: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut buffer = [0u8; 1024];
+                let _ = stream.read(&mut buffer).unwrap();
+                write!(stream, "HTTP/1.0 200 OK\r\n\r\n{}", body).unwrap();
+            }
+        });
+        format!("http://{}/metrics", address)
+    }
+
+    #[test]
+    fn test_serve() {
+        let first = stub("# TYPE up gauge\nup 1\n");
+        let second = stub("# TYPE up gauge\nup 0\n");
+        let config = ServeConfig::from_json(&format!(
+            r#"{{"listen": "127.0.0.1:0", "targets": [
+                {{"url": "{}", "prefix": "a_", "labels": {{"zone": "x"}}}},
+                {{"url": "{}", "prefix": "b_"}},
+                {{"url": "http://127.0.0.1:1/metrics"}}
+            ]}}"#,
+            first, second
+        ))
+        .unwrap();
+        let server = Server::bind(config, Context::new("")).unwrap();
+        let address = server.local_addr().unwrap();
+        let results = server.scrape();
+        assert!(results[0].1.is_ok() && results[1].1.is_ok());
+        assert!(results[2].1.is_err());
+        assert_eq!(
+            server.registry().render(),
+            "# TYPE a_up gauge\na_up{zone=\"x\"} 1\n\n# TYPE b_up gauge\nb_up 0\n\n"
+        );
+
+        let path = std::env::temp_dir().join(format!("promerge-{}.yaml", std::process::id()));
+        std::fs::write(
+            &path,
+            format!(
+                "sources:\n  - url: {}\n    prefix: c_\n  - url: {}\n    prefix: a_\n\
+                 \x20 - url: {}\n    name: again\n    prefix: d_\n",
+                second, first, first
+            ),
+        )
+        .unwrap();
+        server.reload(&path).unwrap();
+        let sources = [second.as_str(), first.as_str(), "again"];
+        assert_eq!(server.registry().sources(), sources);
+        assert_eq!(
+            server.registry().render(),
+            "# TYPE b_up gauge\nb_up 0\n\n# TYPE a_up gauge\na_up{zone=\"x\"} 1\n\n"
+        );
+        std::fs::write(&path, "sources:\n  - url: http://a\n    prefix: 1x\n").unwrap();
+        let err = server.reload(&path).unwrap_err();
+        assert_eq!(err.to_string(), "sources[0].prefix: invalid prefix \"1x\"");
+        std::fs::remove_file(&path).unwrap();
+        assert!(server.scrape().iter().all(|(_, result)| result.is_ok()));
+        std::thread::spawn(move || server.run());
+
+        let output = fetch(
+            &format!("http://{}/metrics", address),
+            Duration::from_secs(5),
+        )
+        .unwrap();
+        assert_eq!(
+            output,
+            "# TYPE c_up gauge\nc_up 0\n\n# TYPE a_up gauge\na_up 1\n\n# TYPE d_up gauge\nd_up 1\n\n"
+        );
+        assert!(fetch(&format!("http://{}/", address), Duration::from_secs(5)).is_err());
+    }
+}
@@ -0,0 +1,127 @@
+//! Module containing per-source staleness handling.
+use std::borrow::Cow;
+use std::time::Duration;
+
+use crate::intern::Label;
+use crate::promerge::Value;
+
+/// Name of the label marking series of stale sources.
+pub const STALE_LABEL: &str = "stale";
+
+/// Action taken on the families of a stale source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StaleAction {
+    /// Leaves the families out of the output.
+    #[default]
+    Omit,
+    /// Sets `stale="true"` on every series, replacing any
+    /// `stale` label of the source.
+    Label,
+    /// Replaces every sample by `NaN`. This is an ordinary NaN
+    /// sample to Prometheus, the staleness marker can not be
+    /// written in the text format.
+    Nan,
+}
+
+/// Staleness treats a source as stale once it was last
+/// combined longer than `ttl` ago.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Staleness {
+    ttl: Duration,
+    action: StaleAction,
+}
+
+impl Staleness {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            action: StaleAction::default(),
+        }
+    }
+
+    pub fn with_action(mut self, action: StaleAction) -> Self {
+        self.action = action;
+        self
+    }
+
+    pub fn is_stale(&self, age: Duration) -> bool {
+        age > self.ttl
+    }
+
+    /// Returns the families of a source last combined `age` ago.
+    pub(crate) fn apply<'a>(&self, values: &[Value<'a>], age: Duration) -> Vec<Value<'a>> {
+        if !self.is_stale(age) {
+            return values.to_vec();
+        }
+        if self.action == StaleAction::Omit {
+            return Vec::new();
+        }
+        let mut values = values.to_vec();
+        for value in &mut values {
+            match self.action {
+                StaleAction::Label => {
+                    for pairs in &mut value.pairs {
+                        mark(pairs);
+                    }
+                    for segment in [&mut value.sum, &mut value.count].into_iter().flatten() {
+                        mark(&mut segment.pairs);
+                    }
+                }
+                _ => {
+                    for sample in &mut value.values {
+                        sample.0 = Cow::Borrowed("NaN");
+                    }
+                    for segment in [&mut value.sum, &mut value.count].into_iter().flatten() {
+                        segment.value = Cow::Borrowed("NaN");
+                    }
+                }
+            }
+        }
+        values
+    }
+}
+
+fn mark(pairs: &mut Vec<(Label<'_>, Label<'_>)>) {
+    match pairs.iter_mut().find(|(k, _)| k == STALE_LABEL) {
+        Some(pair) => pair.1 = "true".into(),
+        None => pairs.push((STALE_LABEL.into(), "true".into())),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::promerge::Context;
+
+    #[test]
+    fn test_staleness() {
+        let input = r#"# HELP http_requests_total The total number of HTTP requests.
+# TYPE http_requests_total counter
+http_requests_total{method="post",code="200"} 1027
+http_requests_total{method="get",stale="false"} 5
+"#;
+        let fresh = r#"# HELP http_requests_total The total number of HTTP requests.
+# TYPE http_requests_total counter
+http_requests_total{method="post",code="200"} 1027
+http_requests_total{method="get",stale="false"} 5
+
+"#;
+        let labeled = r#"# HELP http_requests_total The total number of HTTP requests.
+# TYPE http_requests_total counter
+http_requests_total{method="post",code="200",stale="true"} 1027
+http_requests_total{method="get",stale="true"} 5
+
+"#;
+        let ttl = Duration::from_millis(50);
+        let mut omit = Context::new(input);
+        omit.set_staleness(Staleness::new(ttl));
+        assert_eq!(omit.run().unwrap(), fresh);
+        let mut label = Context::new(input);
+        label.set_staleness(Staleness::new(ttl).with_action(StaleAction::Label));
+        assert_eq!(label.run().unwrap(), fresh);
+
+        std::thread::sleep(ttl * 2);
+        assert_eq!(omit.refresh(), "");
+        assert_eq!(label.refresh(), labeled);
+    }
+}
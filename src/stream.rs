@@ -0,0 +1,339 @@
+//! Module containing a streaming parser over buffered readers.
+use std::collections::VecDeque;
+use std::io::BufRead;
+
+use pest::error::LineColLocation;
+
+use crate::filter::Filter;
+use crate::parser::{self, Rule};
+use crate::promerge::{self, Value};
+
+/// Error returned while reading or parsing a stream.
+#[derive(Debug)]
+pub enum Error {
+    Io(std::io::Error),
+    Parse(Box<pest::error::Error<Rule>>),
+    InvalidName { what: &'static str, name: String },
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "failed to read input: {}", err),
+            Error::Parse(err) => write!(f, "failed to parse input: {}", err),
+            Error::InvalidName { what, name } => write!(f, "invalid {} {:?}", what, name),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+impl From<pest::error::Error<Rule>> for Error {
+    fn from(err: pest::error::Error<Rule>) -> Self {
+        Error::Parse(Box::new(err))
+    }
+}
+
+impl From<promerge::Error> for Error {
+    fn from(err: promerge::Error) -> Self {
+        match err {
+            promerge::Error::Parse(err) => Error::Parse(err),
+            promerge::Error::InvalidName { what, name } => Error::InvalidName { what, name },
+        }
+    }
+}
+
+/// Blocks collects lines into blocks and parses each one
+/// once it is complete.
+#[derive(Default)]
+pub(crate) struct Blocks {
+    filter: Option<Filter>,
+    chunk: String,
+    start: usize,
+    lines: usize,
+    has_samples: bool,
+    pending: VecDeque<Value<'static>>,
+}
+
+impl Blocks {
+    pub(crate) fn new(filter: Option<Filter>) -> Self {
+        Self {
+            filter,
+            ..Self::default()
+        }
+    }
+
+    /// Adds the next `line` of the input, including its newline.
+    pub(crate) fn push_line(&mut self, line: &str) -> Result<(), Error> {
+        self.lines += 1;
+        let trimmed = line.trim_start();
+        if trimmed.starts_with('#') && self.has_samples {
+            self.finish()?;
+            self.start = self.lines - 1;
+        }
+        if !trimmed.is_empty() && !trimmed.starts_with('#') {
+            self.has_samples = true;
+        }
+        self.chunk.push_str(line);
+        Ok(())
+    }
+
+    /// Parses the pending block, e.g. at the end of the input.
+    pub(crate) fn finish(&mut self) -> Result<(), Error> {
+        let chunk = std::mem::take(&mut self.chunk);
+        self.has_samples = false;
+        if chunk.trim().is_empty() {
+            return Ok(());
+        }
+        match parser::parse(&chunk, self.filter.as_ref()) {
+            Ok(values) => {
+                self.pending
+                    .extend(values.into_iter().map(|v| v.into_owned()));
+                Ok(())
+            }
+            Err(mut err) => {
+                err.line_col = match err.line_col {
+                    LineColLocation::Pos((line, col)) => {
+                        LineColLocation::Pos((line + self.start, col))
+                    }
+                    LineColLocation::Span((l1, c1), (l2, c2)) => {
+                        LineColLocation::Span((l1 + self.start, c1), (l2 + self.start, c2))
+                    }
+                };
+                Err(err.into())
+            }
+        }
+    }
+
+    pub(crate) fn pop(&mut self) -> Option<Value<'static>> {
+        self.pending.pop_front()
+    }
+}
+
+/// StreamParser reads an exposition line by line and yields
+/// its families as soon as their block is complete, so that
+/// only a single block is held in memory at a time.
+///
+/// A block ends where a comment line follows sample lines.
+/// Parse errors report line numbers of the whole stream.
+pub struct StreamParser<R> {
+    reader: R,
+    line: String,
+    blocks: Blocks,
+    done: bool,
+}
+
+impl<R: BufRead> StreamParser<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            line: String::new(),
+            blocks: Blocks::new(None),
+            done: false,
+        }
+    }
+
+    /// Skips families rejected by `filter`.
+    pub fn with_filter(mut self, filter: Option<Filter>) -> Self {
+        self.blocks.filter = filter;
+        self
+    }
+
+    fn fill(&mut self) -> Result<(), Error> {
+        self.line.clear();
+        if self.reader.read_line(&mut self.line)? == 0 {
+            self.done = true;
+            return self.blocks.finish();
+        }
+        self.blocks.push_line(&self.line)
+    }
+}
+
+impl<R: BufRead> Iterator for StreamParser<R> {
+    type Item = Result<Value<'static>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(value) = self.blocks.pop() {
+                return Some(Ok(value));
+            }
+            if self.done {
+                return None;
+            }
+            if let Err(err) = self.fill() {
+                self.done = true;
+                return Some(Err(err));
+            }
+        }
+    }
+}
+
+/// AsyncStreamParser is the asynchronous counterpart of
+/// `StreamParser`, reading from a tokio `AsyncBufRead`.
+#[cfg(feature = "tokio")]
+pub struct AsyncStreamParser<R> {
+    reader: R,
+    line: String,
+    blocks: Blocks,
+    done: bool,
+}
+
+#[cfg(feature = "tokio")]
+impl<R: tokio::io::AsyncBufRead + Unpin> AsyncStreamParser<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            line: String::new(),
+            blocks: Blocks::new(None),
+            done: false,
+        }
+    }
+
+    /// Skips families rejected by `filter`.
+    pub fn with_filter(mut self, filter: Option<Filter>) -> Self {
+        self.blocks.filter = filter;
+        self
+    }
+
+    async fn fill(&mut self) -> Result<(), Error> {
+        use tokio::io::AsyncBufReadExt;
+        self.line.clear();
+        if self.reader.read_line(&mut self.line).await? == 0 {
+            self.done = true;
+            return self.blocks.finish();
+        }
+        self.blocks.push_line(&self.line)
+    }
+
+    /// Returns the next family, reading as much of the input
+    /// as needed to complete its block.
+    pub async fn next_family(&mut self) -> Option<Result<Value<'static>, Error>> {
+        loop {
+            if let Some(value) = self.blocks.pop() {
+                return Some(Ok(value));
+            }
+            if self.done {
+                return None;
+            }
+            if let Err(err) = self.fill().await {
+                self.done = true;
+                return Some(Err(err));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::promerge::Context;
+
+    #[test]
+    fn test_stream_parser() {
+        let input = r#"# HELP http_requests_total The total number of HTTP requests.
+# TYPE http_requests_total counter
+http_requests_total{method="post",code="200"} 1027 1395066363000
+http_requests_total{method="post",code="400"} 3 1395066363000
+
+# Minimalistic line:
+metric_without_timestamp_and_labels 12.47
+
+# A histogram, which has a pretty complex representation in the text format:
+# HELP http_request_duration_seconds A histogram of the request duration.
+# TYPE http_request_duration_seconds histogram
+http_request_duration_seconds_bucket{le="0.05"} 24054
+http_request_duration_seconds_bucket{le="+Inf"} 144320
+http_request_duration_seconds_sum 53423
+http_request_duration_seconds_count 144320
+"#;
+        let names: Vec<String> = StreamParser::new(input.as_bytes())
+            .map(|v| v.unwrap().family_name().to_string())
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "http_requests_total",
+                "metric_without_timestamp_and_labels",
+                "http_request_duration_seconds"
+            ]
+        );
+
+        let broken = format!("{}# Broken line:\nbroken{{ 1\n", input);
+        let err = StreamParser::new(broken.as_bytes())
+            .find_map(Result::err)
+            .unwrap();
+        match err {
+            Error::Parse(err) => assert!(matches!(err.line_col, LineColLocation::Pos((17, _)))),
+            err => panic!("unexpected error: {}", err),
+        }
+
+        let pairs = [("instance".to_string(), "a".to_string())];
+        let mut ctx = Context::new("");
+        let expect = ctx
+            .combine_with_prefix_and_pairs(input, &pairs, "prefix_")
+            .unwrap()
+            .to_string();
+        let mut ctx = Context::new("");
+        let output = ctx
+            .combine_reader(input.as_bytes(), &pairs, "prefix_")
+            .unwrap();
+        assert_eq!(output, expect);
+
+        let mut sink: Vec<u8> = Vec::new();
+        let ctx = Context::new("");
+        ctx.stream(input.as_bytes(), &pairs, "prefix_", &mut sink)
+            .unwrap();
+        assert_eq!(String::from_utf8(sink).unwrap(), expect);
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn test_async_stream_parser() {
+        let input = r#"# HELP http_requests_total The total number of HTTP requests.
+# TYPE http_requests_total counter
+http_requests_total{method="post",code="200"} 1027
+
+# Minimalistic line:
+metric_without_timestamp_and_labels 12.47
+"#;
+        let pairs = [("instance".to_string(), "a".to_string())];
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let mut parser = AsyncStreamParser::new(input.as_bytes());
+            let mut names = Vec::new();
+            while let Some(value) = parser.next_family().await {
+                names.push(value.unwrap().family_name().to_string());
+            }
+            assert_eq!(
+                names,
+                ["http_requests_total", "metric_without_timestamp_and_labels"]
+            );
+
+            let mut expect = Context::new("");
+            expect
+                .combine_with_prefix_and_pairs(input, &pairs, "prefix_")
+                .unwrap();
+            let mut ctx = Context::new("");
+            ctx.combine_async(input.as_bytes(), &pairs, "prefix_")
+                .await
+                .unwrap();
+            let mut sink: Vec<u8> = Vec::new();
+            ctx.write_async(&mut sink).await.unwrap();
+            assert_eq!(String::from_utf8(sink).unwrap(), expect.result());
+
+            let mut sink: Vec<u8> = Vec::new();
+            ctx.stream_async(input.as_bytes(), &pairs, "prefix_", &mut sink)
+                .await
+                .unwrap();
+            assert_eq!(String::from_utf8(sink).unwrap(), expect.result());
+        });
+    }
+}
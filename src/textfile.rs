@@ -0,0 +1,232 @@
+//! Module containing the textfile collector directory mode.
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crate::promerge::Context;
+use crate::stream::Error;
+
+/// Name of the label holding the file a series was read from.
+pub const SOURCE_LABEL: &str = "source";
+
+/// Textfile merges the `*.prom` files of a directory, as read
+/// by the node_exporter textfile collector, into a single
+/// output file.
+///
+/// Every series gets a label holding the file name without
+/// its extension, replacing a label of that name it had. Files are only parsed again once modified.
+/// A file that can not be read or parsed is rejected and keeps
+/// the contribution it had when it last parsed, so a partially
+/// written file never reaches the output. The output is
+/// written to a temporary file next to it, then renamed over
+/// it, so readers never see a partial output either.
+pub struct Textfile {
+    directory: PathBuf,
+    output: PathBuf,
+    label: String,
+    context: Context<'static>,
+    modified: HashMap<String, Option<(SystemTime, u64)>>,
+}
+
+impl Textfile {
+    /// Merges the files of `directory` into `output` with the
+    /// configuration of `context`.
+    pub fn new<D, O>(directory: D, output: O, context: Context<'static>) -> Self
+    where
+        D: Into<PathBuf>,
+        O: Into<PathBuf>,
+    {
+        Self {
+            directory: directory.into(),
+            output: output.into(),
+            label: SOURCE_LABEL.into(),
+            context,
+            modified: HashMap::new(),
+        }
+    }
+
+    /// Uses `label` instead of `source` for the file name.
+    pub fn with_label<S: Into<String>>(mut self, label: S) -> Self {
+        self.label = label.into();
+        self
+    }
+
+    /// Returns the names of the `*.prom` files of the directory,
+    /// leaving out the output itself.
+    fn files(&self) -> std::io::Result<Vec<String>> {
+        let output = self.output.canonicalize().ok();
+        let mut files = Vec::new();
+        for entry in std::fs::read_dir(&self.directory)? {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("prom") || !path.is_file() {
+                continue;
+            }
+            if output.is_some() && path.canonicalize().ok() == output {
+                continue;
+            }
+            if let Some(name) = path.file_name().and_then(|n| n.to_str()) {
+                files.push(name.to_string());
+            }
+        }
+        files.sort();
+        Ok(files)
+    }
+
+    fn read(&mut self, name: &str) -> Result<(), Error> {
+        let input = std::fs::read_to_string(self.directory.join(name))?;
+        let stem = name.strip_suffix(".prom").unwrap_or(name);
+        let pairs = [(self.label.clone(), stem.to_string())];
+        self.context.update_source_owned(name, &input, &pairs, "")?;
+        Ok(())
+    }
+
+    /// Merges the files modified since the last call, drops the
+    /// ones that were removed and replaces the output if
+    /// anything changed. Returns the rejected files.
+    pub fn collect(&mut self) -> std::io::Result<Vec<(String, Error)>> {
+        let files = self.files()?;
+        let mut rejected = Vec::new();
+        let mut changed = false;
+        let removed: Vec<String> = self
+            .modified
+            .keys()
+            .filter(|name| !files.contains(name))
+            .cloned()
+            .collect();
+        for name in removed {
+            self.modified.remove(&name);
+            changed |= self.context.remove_source(&name);
+        }
+        for name in files {
+            let modified = std::fs::metadata(self.directory.join(&name))
+                .and_then(|m| Ok((m.modified()?, m.len())))
+                .ok();
+            if modified.is_some() && self.modified.get(&name) == Some(&modified) {
+                continue;
+            }
+            self.modified.insert(name.clone(), modified);
+            match self.read(&name) {
+                Ok(()) => changed = true,
+                Err(err) => rejected.push((name, err)),
+            }
+        }
+        if changed || !self.output.exists() {
+            write_atomic(&self.output, self.context.result().as_bytes())?;
+        }
+        Ok(rejected)
+    }
+
+    /// Collects every `interval` until the directory or the
+    /// output can not be accessed.
+    pub fn run(mut self, interval: Duration) -> std::io::Result<()> {
+        loop {
+            for (name, err) in self.collect()? {
+                eprintln!("promerge: rejected {}: {}", name, err);
+            }
+            std::thread::sleep(interval);
+        }
+    }
+}
+
+/// Replaces `path` with `contents` by renaming a temporary file
+/// written next to it.
+pub fn write_atomic<P: AsRef<Path>>(path: P, contents: &[u8]) -> std::io::Result<()> {
+    let path = path.as_ref();
+    let name = path.file_name().and_then(|n| n.to_str()).ok_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, "invalid output path")
+    })?;
+    let temporary = path.with_file_name(format!(".{}.{}.tmp", name, std::process::id()));
+    let result = std::fs::File::create(&temporary).and_then(|mut file| {
+        file.write_all(contents)?;
+        file.sync_all()
+    });
+    match result.and_then(|()| std::fs::rename(&temporary, path)) {
+        Ok(()) => Ok(()),
+        Err(err) => {
+            let _ = std::fs::remove_file(&temporary);
+            Err(err)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_textfile() {
+        let directory =
+            std::env::temp_dir().join(format!("promerge-textfile-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        std::fs::create_dir(&directory).unwrap();
+        let output = directory.join("merged.prom");
+        std::fs::write(
+            directory.join("backup.prom"),
+            "# TYPE backup_last_success gauge\nbackup_last_success 1700000000\n",
+        )
+        .unwrap();
+        std::fs::write(
+            directory.join("cleanup.prom"),
+            "# TYPE cleanup_removed_total counter\ncleanup_removed_total 3\n",
+        )
+        .unwrap();
+        std::fs::write(directory.join("notes.txt"), "broken{").unwrap();
+
+        let mut textfile = Textfile::new(&directory, &output, Context::new(""));
+        assert!(textfile.collect().unwrap().is_empty());
+        let expect = r#"# TYPE backup_last_success gauge
+backup_last_success{source="backup"} 1700000000
+
+# TYPE cleanup_removed_total counter
+cleanup_removed_total{source="cleanup"} 15
+
+"#;
+        // The output is not merged into itself on the next run.
+        std::fs::write(
+            directory.join("cleanup.prom"),
+            "# TYPE cleanup_removed_total counter\ncleanup_removed_total 15\n",
+        )
+        .unwrap();
+        std::fs::write(
+            directory.join("broken.prom"),
+            "# TYPE broken gauge\nbroken{ 1\n",
+        )
+        .unwrap();
+        let rejected = textfile.collect().unwrap();
+        assert_eq!(rejected.len(), 1);
+        assert_eq!(rejected[0].0, "broken.prom");
+        assert!(matches!(rejected[0].1, Error::Parse(_)));
+        assert_eq!(std::fs::read_to_string(&output).unwrap(), expect);
+
+        std::fs::remove_file(directory.join("backup.prom")).unwrap();
+        assert!(textfile.collect().unwrap().is_empty());
+        assert_eq!(
+            std::fs::read_to_string(&output).unwrap(),
+            "# TYPE cleanup_removed_total counter\ncleanup_removed_total{source=\"cleanup\"} 15\n\n"
+        );
+
+        // The label replaces a label of the same name.
+        std::fs::write(
+            directory.join("jobs.prom"),
+            "# TYPE jobs_done gauge\njobs_done{source=\"cron\"} 1\n",
+        )
+        .unwrap();
+        assert!(textfile.collect().unwrap().is_empty());
+        assert!(std::fs::read_to_string(&output)
+            .unwrap()
+            .contains("jobs_done{source=\"jobs\"} 1\n"));
+
+        // A partially written file keeps its previous contribution.
+        std::fs::write(
+            directory.join("cleanup.prom"),
+            "# TYPE cleanup_removed_total counter\ncleanup_removed_total{",
+        )
+        .unwrap();
+        assert_eq!(textfile.collect().unwrap().len(), 1);
+        assert!(std::fs::read_to_string(&output)
+            .unwrap()
+            .contains("cleanup_removed_total{source=\"cleanup\"} 15"));
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
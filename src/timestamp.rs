@@ -0,0 +1,120 @@
+//! Module containing handling of sample timestamps.
+use std::borrow::Cow;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::promerge::Value;
+
+/// TimestampPolicy decides which timestamp, in milliseconds
+/// since the epoch, every emitted sample carries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TimestampPolicy {
+    /// Emits timestamps as exposed by the input.
+    #[default]
+    Keep,
+    /// Removes timestamps, letting Prometheus assign the scrape time.
+    Strip,
+    /// Stamps every sample with the given timestamp.
+    Override(i64),
+    /// Moves existing timestamps by the given offset.
+    Shift(i64),
+    /// Stamps every sample with the time the output is
+    /// rendered, so `Context::refresh` moves it forward.
+    Now,
+}
+
+impl TimestampPolicy {
+    /// Returns the policy with `Now` replaced by the current time.
+    pub fn resolve(&self) -> Self {
+        match self {
+            TimestampPolicy::Now => {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |d| d.as_millis() as i64);
+                TimestampPolicy::Override(now)
+            }
+            policy => *policy,
+        }
+    }
+
+    /// Rewrites the timestamps of every series of `value`.
+    /// Timestamps which are not integers are kept as is
+    /// when shifting.
+    pub fn apply(&self, value: &mut Value<'_>) {
+        let policy = self.resolve();
+        let segments = value.sum.iter_mut().chain(value.count.iter_mut());
+        let timestamps = value
+            .values
+            .iter_mut()
+            .map(|(_, t)| t)
+            .chain(segments.map(|s| &mut s.timestamp));
+        for timestamp in timestamps {
+            match policy {
+                TimestampPolicy::Keep | TimestampPolicy::Now => {}
+                TimestampPolicy::Strip => *timestamp = None,
+                TimestampPolicy::Override(ts) => *timestamp = Some(Cow::Owned(ts.to_string())),
+                TimestampPolicy::Shift(offset) => {
+                    if let Some(ts) = timestamp.as_ref().and_then(|t| t.parse::<i64>().ok()) {
+                        *timestamp = Some(Cow::Owned(ts.saturating_add(offset).to_string()));
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::aggregate::Aggregation;
+    use crate::promerge::Context;
+
+    #[test]
+    fn test_timestamp_policy() {
+        let input = r#"# HELP http_requests_total The total number of HTTP requests.
+# TYPE http_requests_total counter
+http_requests_total{method="post",code="200"} 1027 1395066363000
+http_requests_total{method="post",code="400"} 3
+"#;
+        let cases = [
+            (TimestampPolicy::Strip, "", ""),
+            (
+                TimestampPolicy::Override(1700000000000),
+                " 1700000000000",
+                " 1700000000000",
+            ),
+            (TimestampPolicy::Shift(-3000), " 1395066360000", ""),
+        ];
+        for (policy, first, second) in cases {
+            let expect = format!(
+                r#"# HELP http_requests_total The total number of HTTP requests.
+# TYPE http_requests_total counter
+http_requests_total{{method="post",code="200"}} 1027{}
+http_requests_total{{method="post",code="400"}} 3{}
+
+"#,
+                first, second
+            );
+            let mut ctx = Context::new(input);
+            ctx.set_timestamp_policy(policy);
+            assert_eq!(ctx.run().unwrap(), expect);
+        }
+
+        // Aggregated series are stamped too.
+        let input = "# TYPE c counter\nc{instance=\"a\"} 1\n";
+        let mut ctx = Context::new(input);
+        ctx.set_aggregation(Aggregation::sum_without(["instance"]));
+        ctx.set_timestamp_policy(TimestampPolicy::Override(42));
+        assert_eq!(ctx.run().unwrap(), "# TYPE c counter\nc 1 42\n\n");
+
+        // The current time is taken whenever the output is rendered.
+        let mut ctx = Context::new(input);
+        ctx.set_timestamp_policy(TimestampPolicy::Now);
+        let stamp = |output: &str| -> i64 {
+            let line = output.lines().nth(1).unwrap();
+            line.rsplit(' ').next().unwrap().parse().unwrap()
+        };
+        let first = stamp(ctx.run().unwrap());
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        assert!(stamp(ctx.refresh()) > first);
+    }
+}